@@ -2,26 +2,148 @@
 
 var addon = require('../native');
 const net = require('net');
+const EventEmitter = require('events');
+const workerThreads = require('worker_threads');
 var async = require('async');
+var serve = require('./serve');
 
 
+// only the first worker thread that loads noise starts the listener, all
+// others share it
 addon.startListener();
 
-var connectionId = 0;
+// how long shutting down waits for the open instances by default
+var SHUTDOWN_TIMEOUT = 10000;
 
-var newDb = function(q, socket, connId) {
+// set once noise.shutdown() was called
+var shutDown = false;
+var shutdownPromise = null;
+// the instances that weren't closed yet
+var openDbs = new Set();
+// keeps a daemon process running, its connections are served by native
+// threads that node doesn't know about
+var keepAlive = null;
+
+// The exit hooks shut down noise for the whole process, so only the main
+// thread installs them. The instances of a worker thread that ends are closed
+// when their connections end.
+if (workerThreads.isMainThread) {
+    // close everything on exit. Only synchronous code runs now, so
+    // instead of waiting, what is still open is closed by force. In-memory
+    // and temporary indexes are removed as well.
+    process.on('exit', () => {
+        shutDown = true;
+        addon.shutdown(0);
+    });
+
+    // the 'exit' event isn't emitted when the process is killed by a signal.
+    // If the application doesn't handle them, shut down before the signal
+    // takes effect.
+    ['SIGINT', 'SIGTERM'].forEach(signal => {
+        var onSignal = () => {
+            // the application handles the signal, even if it only added its
+            // listener after noise was loaded
+            if (process.listenerCount(signal) > 1) {
+                return;
+            }
+            // a second signal kills the process right away
+            process.removeListener(signal, onSignal);
+            module.exports.shutdown().then(() => {
+                process.kill(process.pid, signal);
+            });
+        };
+        if (process.listenerCount(signal) == 0) {
+            process.on(signal, onSignal);
+        }
+    });
+}
+
+// The size of the header of a frame, see native/core/src/wire.rs
+var FRAME_HEADER_SIZE = 10;
+
+// Emits a 'frame' event for each complete frame received on the socket.
+var frameReader = function(socket) {
+    var frames = new EventEmitter();
+    var chunks = [];
+    var length = 0;
+    socket.on('data', data => {
+        chunks.push(data);
+        length += data.length;
+        while (length >= FRAME_HEADER_SIZE) {
+            var buffer = chunks.length == 1 ? chunks[0] : Buffer.concat(chunks, length);
+            var size = FRAME_HEADER_SIZE + buffer.readUInt32BE(6);
+            if (length < size) {
+                chunks = [buffer];
+                break;
+            }
+            var rest = buffer.subarray(size);
+            chunks = rest.length > 0 ? [rest] : [];
+            length = rest.length;
+            frames.emit('frame', buffer.subarray(0, size));
+        }
+    });
+    return frames;
+};
+
+var newDb = function(q, socket, connId, daemon) {
+    var db = this;
     var openError = null;
-    var sendAndReceive = (callback, resolve, reject, msgType, argsFun) => {
+    var frames = frameReader(socket);
+    // the id of the last request sent, the open request is the first one
+    var requestId = 0;
+    var connected = false;
+    // the request that is waiting for its response
+    var pending = null;
+    var closed = false;
+    // the serving thread only ends the connection by itself when the index
+    // gets dropped by force. Fail the waiting and all further requests.
+    var connectionLost = (error) => {
+        if (!openError) {
+            openError = error;
+        }
+        if (pending) {
+            var waiting = pending;
+            pending = null;
+            frames.removeListener('frame', waiting.localCb);
+            try {
+                waiting.reject(openError);
+            } finally {
+                waiting.callback(openError);
+            }
+        }
+    };
+    var lostError = () => {
+        if (shutDown) {
+            return new Error("noise is shut down");
+        }
+        if (!connected && daemon) {
+            return new Error("Can't connect to the noise daemon at " + daemon);
+        }
+        return new Error(closed ? "index closed" : "index dropped");
+    };
+    socket.on('connect', () => {
+        connected = true;
+    });
+    socket.on('end', () => connectionLost(lostError()));
+    // writing a request after the connection ended fails as well
+    socket.on('error', () => connectionLost(lostError()));
+    socket.on('close', () => addon.releaseConnection(connId));
+    var send = (msgType, args, options) => {
+        requestId = (requestId + 1) % 0x100000000;
+        socket.write(addon.encodeRequest(requestId, msgType, args, options || {}));
+    };
+    // puts the response in the message slot, or throws if it's not the
+    // response to the last request
+    var receive = (frame) => {
+        addon.receiveResponse(connId, requestId, frame);
+    };
+    var sendAndReceive = (callback, resolve, reject, msgType, argsFun, options) => {
         try {
             // If the index couldn't be opened propogate the error.
             if (openError) {
                 throw openError;
             }
-            var args = argsFun();
-            // call the native function and put data into the message slot
-            addon.sendMessage(connId, msgType, args);
-            //notify the waiting thread a message is waiting
-            socket.write("0");
+            send(msgType, argsFun(), options);
         } catch(e) {
             try {
                 reject(e);
@@ -30,11 +152,13 @@ var newDb = function(q, socket, connId) {
             }
             return;
         }
-        var localCb = (_buffer) => {
+        var localCb = (frame) => {
+            pending = null;
             try {
                 // we must remove the listener or it keeps getting notifications
-                socket.removeListener('data', localCb);
+                frames.removeListener('frame', localCb);
                 // get the response. on an error it throws exception
+                receive(frame);
                 var resp = addon.getResponse(connId);
             } catch(e) {
                 try {
@@ -51,9 +175,10 @@ var newDb = function(q, socket, connId) {
             }
         };
         // wait for the response
-        socket.on('data', localCb);
+        pending = {localCb: localCb, reject: reject, callback: callback};
+        frames.on('frame', localCb);
     }
-    this.add = function(json) {
+    this.add = function(json, options) {
         return new Promise((resolve, reject) => {
             q.push((callback) => {
                 sendAndReceive(callback, resolve, reject, 2, () => {
@@ -68,7 +193,7 @@ var newDb = function(q, socket, connId) {
                         var input = [JSON.stringify(json)];
                     }
                     return input;
-                });
+                }, options);
             });
         });
     };
@@ -103,10 +228,7 @@ var newDb = function(q, socket, connId) {
                     } else {
                         var msg = [query];
                     }
-                    // call the native function and put data into the message slot
-                    addon.sendMessage(connId, 4, msg);
-                    //notify the waiting thread a message is waiting
-                    socket.write("0");
+                    send(4, msg);
                 } catch(e) {
                     try {
                         reject(e);
@@ -115,10 +237,12 @@ var newDb = function(q, socket, connId) {
                     }
                     return;
                 }
-                var localCb = (_buffer) => {
+                var localCb = (frame) => {
+                    pending = null;
                     try {
                         // we must remove the listener or it keeps getting notifications
-                        socket.removeListener('data', localCb);
+                        frames.removeListener('frame', localCb);
+                        receive(frame);
                         addon.getError(connId);
                     } catch(e) {
                         try {
@@ -157,7 +281,81 @@ var newDb = function(q, socket, connId) {
                     resolve(iter);
                 };
                 // wait for the response
-                socket.on('data', localCb);
+                pending = {localCb: localCb, reject: reject, callback: callback};
+                frames.on('frame', localCb);
+            });
+        });
+    };
+
+    this.registerQuery = function(name, query, params) {
+        return new Promise((resolve, reject) => {
+            q.push((callback) => {
+                sendAndReceive(callback, resolve, reject, 6, () => {
+                    if (params != undefined) {
+                        return [name, query, JSON.stringify(params)];
+                    } else {
+                        return [name, query];
+                    }
+                });
+            });
+        });
+    };
+
+    this.unregisterQuery = function(name) {
+        return new Promise((resolve, reject) => {
+            q.push((callback) => {
+                sendAndReceive(callback, resolve, reject, 7, () => {
+                    return [name];
+                });
+            });
+        });
+    };
+
+    this.info = function() {
+        return new Promise((resolve, reject) => {
+            q.push((callback) => {
+                sendAndReceive(callback, resolve, reject, 8, () => {
+                    return [];
+                });
+            });
+        });
+    };
+
+    this.schema = function(options) {
+        return new Promise((resolve, reject) => {
+            q.push((callback) => {
+                sendAndReceive(callback, resolve, reject, 9, () => {
+                    if (options != undefined && options.sampleSize != undefined) {
+                        return [options.sampleSize];
+                    } else {
+                        return [];
+                    }
+                });
+            });
+        });
+    };
+
+    this.backup = function(backupDir, options) {
+        return new Promise((resolve, reject) => {
+            q.push((callback) => {
+                sendAndReceive(callback, resolve, reject, 10, () => {
+                    if (options != undefined && options.keep != undefined) {
+                        return [backupDir, options.keep];
+                    } else {
+                        return [backupDir];
+                    }
+                });
+            });
+        });
+    };
+
+    this.compact = function(options) {
+        return new Promise((resolve, reject) => {
+            q.push((callback) => {
+                // the addon rejects any options, compact has none
+                sendAndReceive(callback, resolve, reject, 14, () => {
+                    return [];
+                }, options);
             });
         });
     };
@@ -168,6 +366,7 @@ var newDb = function(q, socket, connId) {
                 try {
                     // If the index couldn't be opened we are done
                     if (openError) {
+                        openDbs.delete(db);
                         try {
                             resolve();
                         } finally {
@@ -175,10 +374,8 @@ var newDb = function(q, socket, connId) {
                         }
                         return;
                     }
-                    // call the native function and put message into the message slot
-                    addon.sendMessage(connId, 5, []);
-                    //notify the waiting thread a message is waiting
-                    socket.write("0");
+                    closed = true;
+                    send(5, []);
                 } catch(e) {
                     try {
                         reject(e);
@@ -189,6 +386,7 @@ var newDb = function(q, socket, connId) {
                 }
                 // wait for the socket to close (that means the serving thread stopped)
                 socket.on('end', () => {
+                    openDbs.delete(db);
                     try {
                         resolve();
                     } finally {
@@ -200,42 +398,97 @@ var newDb = function(q, socket, connId) {
     };
     // now push the open async handler into the queue.
     q.push((callback) => {
-        var localCb = (_buffer) => {
+        var localCb = (frame) => {
+            pending = null;
             try {
                 // remove this callback or we'll keep getting events to it
-                socket.removeListener('data', localCb);
+                frames.removeListener('frame', localCb);
                 // get_response will throw if error
+                receive(frame);
                 var _ = addon.getResponse(connId);
             } catch(e) {
                 openError = e;
+                socket.end();
                 callback(e);
                 return;
             }
             callback();
         };
-        socket.on('data', localCb);
+        // if connecting fails the open fails
+        if (openError) {
+            callback(openError);
+            return;
+        }
+        pending = {localCb: localCb, reject: () => {}, callback: callback};
+        frames.on('frame', localCb);
     });
 }
 
+// Sends a message that isn't for an opened index to a serving thread, or to
+// the daemon given with the `daemon` option, and returns a promise for the
+// response.
+var sendIndexMessage = function(msgType, args, options) {
+    options = options || {};
+    // the connectionId is the address of the message slot the response is
+    // put in. It's unique across all worker threads.
+    var connId = addon.nextConnectionId();
+
+    return new Promise((resolve, reject) => {
+        var socket = net.connect(options.daemon || "echo.sock", () => {
+            try {
+                socket.write(addon.encodeRequest(0, msgType, args, options));
+            } catch(e) {
+                socket.end();
+                reject(e);
+                return;
+            }
+            frameReader(socket).once('frame', frame => {
+                try {
+                    // get_response will throw if error
+                    addon.receiveResponse(connId, 0, frame);
+                    var resp = addon.getResponse(connId);
+                } catch(e) {
+                    reject(e);
+                    return;
+                } finally {
+                    socket.end();
+                    addon.releaseConnection(connId);
+                }
+                resolve(resp);
+            });
+        });
+        socket.on('error', error => {
+            if (shutDown) {
+                reject(new Error("noise is shut down"));
+            } else if (options.daemon) {
+                reject(new Error("Can't connect to the noise daemon at " + options.daemon));
+            } else {
+                reject(error);
+            }
+        });
+    });
+};
+
 module.exports = {
-    open: function(name) {
-        if (arguments.length == 1) {
-            var createIfMissing = false;
-        } else if (arguments.length > 1) {
-            var createIfMissing = arguments[1];
+    open: function(name, options) {
+        if (options == undefined) {
+            options = {};
+        } else if (typeof options == "boolean") {
+            // the second argument used to be createIfMissing
+            options = {createIfMissing: options};
         }
-        // the connectionId is used as a slot address for sending messages to the
-        // serving thread
-        var connId = (connectionId++);
-
-        var socket = net.connect("echo.sock", () => {
-            // we've connected. Now send the connectionId so both sides
-            // know the common message slot.
-            socket.write(connId.toString() + ";");
-            // put the message in the slot
-            addon.sendMessage(connId, 0, [name, createIfMissing]);
-            //notify the thread we did it
-            socket.write("0");
+        // the connectionId is the address of the message slot the responses
+        // are put in. It's unique across all worker threads.
+        var connId = addon.nextConnectionId();
+
+        // a daemon serves the index the same way the serving threads of this
+        // process do
+        var socketPath = options.daemon || "echo.sock";
+        // encoding throws on unknown options, before anything is opened
+        var openRequest = addon.encodeRequest(0, 0, [name, options]);
+        var socket = net.connect(socketPath, () => {
+            // the open request is the first one
+            socket.write(openRequest);
         });
         // we create a queue of async commands. The first command we'll use it
         // is open. Then any subsequent commands will run after complete
@@ -244,46 +497,106 @@ module.exports = {
         var q = async.queue((task, callback) => {
             task(callback);
         }, 1);
-        var db = new newDb(q, socket, connId);
+        var db = new newDb(q, socket, connId, options.daemon);
+        openDbs.add(db);
+        return db;
+    },
+
+    openTemp: function(prefix) {
+        // the process id lets later processes find the indexes left behind
+        // by ones that crashed
+        var name = prefix + "-tmp-" + process.pid + "-" +
+            Date.now().toString(36) + Math.random().toString(36).slice(2, 8);
+        var db = module.exports.open(name, {createIfMissing: true,
+                                            errorIfExists: true,
+                                            temp: true});
+        db.name = name;
         return db;
     },
 
-    drop: function(name) {
-        // the connectionId is used as a slot address for sending messages to the
-        // serving thread
-        var connId = (connectionId++);
+    shutdown: function(options) {
+        if (shutdownPromise) {
+            return shutdownPromise;
+        }
+        shutDown = true;
+        var timeout = SHUTDOWN_TIMEOUT;
+        if (options != undefined && options.timeout != undefined) {
+            timeout = options.timeout;
+        }
+        var start = Date.now();
+        // every instance finishes its queued requests before it's closed
+        var closing = Promise.all(Array.from(openDbs).map(db => {
+            return db.close().catch(() => {});
+        }));
+        var timer;
+        var timedOut = new Promise(resolve => {
+            timer = setTimeout(resolve, timeout);
+        });
+        shutdownPromise = Promise.race([closing, timedOut]).then(() => {
+            clearTimeout(timer);
+            clearInterval(keepAlive);
+            if (!workerThreads.isMainThread) {
+                // a worker thread only closes its own instances
+                return openDbs.size == 0;
+            }
+            // close whatever is left by force and remove the socket
+            return addon.shutdown(Math.max(0, timeout - (Date.now() - start)));
+        });
+        return shutdownPromise;
+    },
 
-        // now create the promise for when the database deletes
+    startDaemon: function(socketPath) {
         return new Promise((resolve, reject) => {
-            var socket = net.connect("echo.sock", () => {
-                try {
-                    // we've connected. Now send the connectionId so both sides
-                    // know the common message slot.
-                    socket.write(connId.toString() + ";");
-                    // put the message in the slot
-                    addon.sendMessage(connId, 1, [name]);
-                    //notify the thread we did it
-                    socket.write("0");
-                } catch(e) {
-                    reject(e);
-                    return;
-                }
-                var localCb = (_buffer) => {
-                    try {
-                        // remove this callback or we'll keep getting events to it
-                        socket.removeListener('data', localCb);
-                        // get_response will throw if error
-                        var resp = addon.getResponse(connId);
-                        socket.end();
-                    } catch(e) {
-                        socket.end();
-                        reject(e);
-                        return;
-                    }
-                    resolve(resp);
-                };
-                socket.on('data', localCb);
-            });
+            try {
+                addon.startDaemon(socketPath);
+            } catch(e) {
+                reject(e);
+                return;
+            }
+            if (!keepAlive) {
+                keepAlive = setInterval(() => {}, 1 << 30);
+            }
+            resolve();
         });
+    },
+
+    serve: function(options) {
+        return serve(options || {});
+    },
+
+    drop: function(name, options) {
+        return sendIndexMessage(1, [name], options);
+    },
+
+    setBaseDir: function(dir) {
+        addon.setBaseDir(dir);
+    },
+
+    restore: function(backupDir, name, options) {
+        if (options != undefined && options.backupId != undefined) {
+            return sendIndexMessage(11, [backupDir, name, options.backupId], options);
+        } else {
+            return sendIndexMessage(11, [backupDir, name], options);
+        }
+    },
+
+    backups: function(backupDir, options) {
+        return sendIndexMessage(12, [backupDir], options);
+    },
+
+    memoryUsage: function(options) {
+        return sendIndexMessage(13, [], options);
+    },
+
+    list: function(dir, options) {
+        return sendIndexMessage(15, [dir], options);
+    },
+
+    copy: function(src, dest, options) {
+        return sendIndexMessage(16, [src, dest], options);
+    },
+
+    rename: function(src, dest, options) {
+        return sendIndexMessage(17, [src, dest], options);
     }
 };
@@ -0,0 +1,209 @@
+'use strict';
+
+// A local HTTP API for open indexes, so tools that aren't written for Node.js
+// can use them. The requests go through the same index instances as the
+// Javascript API.
+
+const http = require('http');
+
+// the largest request body accepted by default
+var MAX_BODY_SIZE = 16 << 20;
+
+// An error answered with the given status.
+var httpError = function(status, message) {
+    var error = new Error(message);
+    error.status = status;
+    return error;
+};
+
+var sendJson = function(res, status, json) {
+    var body = JSON.stringify(json);
+    res.writeHead(status, {'Content-Type': 'application/json',
+                           'Content-Length': Buffer.byteLength(body)});
+    res.end(body);
+};
+
+var sendError = function(res, status, error) {
+    sendJson(res, status, {error: error instanceof Error ? error.message : error});
+};
+
+// Reads the body of a request, which must be JSON of at most `maxBodySize`
+// bytes.
+var readJson = function(req, maxBodySize) {
+    return new Promise((resolve, reject) => {
+        var type = (req.headers['content-type'] || "").split(";")[0].trim().toLowerCase();
+        if (type != "application/json") {
+            // also keeps browsers from posting forms to the API
+            reject(httpError(415, "content type must be application/json"));
+            return;
+        }
+        if (Number(req.headers['content-length']) > maxBodySize) {
+            reject(httpError(413, "request body is larger than " + maxBodySize + " bytes"));
+            return;
+        }
+        var chunks = [];
+        var length = 0;
+        req.on('data', chunk => {
+            length += chunk.length;
+            if (length > maxBodySize) {
+                req.removeAllListeners('data');
+                req.removeAllListeners('end');
+                req.resume();
+                reject(httpError(413, "request body is larger than " + maxBodySize + " bytes"));
+                return;
+            }
+            chunks.push(chunk);
+        });
+        req.on('end', () => {
+            try {
+                resolve(JSON.parse(Buffer.concat(chunks).toString('utf8')));
+            } catch(e) {
+                reject(new Error("invalid JSON: " + e.message));
+            }
+        });
+        req.on('error', reject);
+    });
+};
+
+// Writes each result as a line of JSON. Stops early if the client goes away.
+var streamResults = function(res, iter) {
+    // the instance serves no other requests until the results are released.
+    // They're in memory already, so take them all rather than waiting for a
+    // slow client.
+    var results = [...iter];
+    res.writeHead(200, {'Content-Type': 'application/x-ndjson'});
+    var done = false;
+    res.on('close', () => {
+        done = true;
+    });
+    var i = 0;
+    var write = () => {
+        while (!done) {
+            if (i == results.length) {
+                done = true;
+                res.end();
+                return;
+            }
+            if (!res.write(JSON.stringify(results[i++]) + "\n")) {
+                res.once('drain', write);
+                return;
+            }
+        }
+    };
+    write();
+};
+
+// Whether a browser sent the request from a page of another origin. Such
+// requests mustn't change anything.
+var isForeignOrigin = function(req) {
+    var origin = req.headers.origin;
+    if (origin == undefined) {
+        return false;
+    }
+    try {
+        return new URL(origin).host != req.headers.host;
+    } catch(e) {
+        // an opaque origin like "null"
+        return true;
+    }
+};
+
+// Whether the server listens on `host` only for the local machine.
+var isLoopback = function(host) {
+    return host == "localhost" || host == "::1" || /^127\./.test(host);
+};
+
+// Whether the Host header names the local machine. A website could otherwise
+// point its own name at 127.0.0.1 once a page is loaded (DNS rebinding), so
+// the page could use the API as if it was of the same origin.
+var isLocalHost = function(req) {
+    return /^(localhost|127\.0\.0\.1|\[::1\])(:\d+)?$/i.test(req.headers.host || "");
+};
+
+var handle = function(indexes, maxBodySize, loopback, req, res) {
+    var url = new URL(req.url, "http://localhost");
+    if (loopback && !isLocalHost(req)) {
+        return sendError(res, 403, "requests for other hosts aren't allowed");
+    }
+    if (req.method != "GET" && isForeignOrigin(req)) {
+        return sendError(res, 403, "requests from other origins aren't allowed");
+    }
+    var path = url.pathname.split("/").filter(part => part != "").map(decodeURIComponent);
+    if (path.length == 0) {
+        if (req.method != "GET") {
+            return sendError(res, 405, "method not allowed");
+        }
+        return sendJson(res, 200, Object.keys(indexes));
+    }
+    var index = Object.prototype.hasOwnProperty.call(indexes, path[0]) ? indexes[path[0]]
+                                                                        : undefined;
+    if (index == undefined) {
+        return sendError(res, 404, "unknown index: " + path[0]);
+    }
+    if (req.method == "POST" && path.length == 2 && path[1] == "docs") {
+        // add one document or an array of them
+        return readJson(req, maxBodySize).then(docs => {
+            if (docs === null || typeof docs != "object") {
+                throw new Error("documents must be objects");
+            }
+            return index.add(docs).then(ids => sendJson(res, 200, ids), error => {
+                sendError(res, 500, error);
+            });
+        }).catch(error => sendError(res, error.status || 400, error));
+    } else if (req.method == "DELETE" && path.length == 3 && path[1] == "docs") {
+        return index.delete(path[2]).then(deleted => {
+            if (deleted[0] === true) {
+                sendJson(res, 200, {deleted: true});
+            } else {
+                sendError(res, 404, "document not found: " + path[2]);
+            }
+        }, error => sendError(res, 500, error));
+    } else if ((req.method == "POST" || req.method == "GET") && path.length == 2 &&
+               path[1] == "query") {
+        // the query and its parameters are either in the body or in the URL
+        var request = req.method == "POST" ? readJson(req, maxBodySize) : new Promise(resolve => {
+            var params = url.searchParams.get("params");
+            resolve({query: url.searchParams.get("q"),
+                     params: params == null ? undefined : JSON.parse(params)});
+        });
+        return request.then(request => {
+            if (request === null || typeof request.query != "string") {
+                throw new Error("query missing");
+            }
+            return index.query(request.query, request.params || undefined).then(iter => {
+                streamResults(res, iter);
+            });
+        }).catch(error => sendError(res, error.status || 400, error));
+    } else if (req.method == "GET" && path.length == 2 && path[1] == "info") {
+        return index.info().then(info => sendJson(res, 200, info),
+                                 error => sendError(res, 500, error));
+    } else {
+        return sendError(res, 404, "not found");
+    }
+};
+
+// Serves the indexes, given as an object of open index instances keyed by
+// the name they're served as. Returns a promise for the server once it's
+// listening.
+module.exports = function(options) {
+    var indexes = options.indexes || {};
+    var maxBodySize = options.maxBodySize || MAX_BODY_SIZE;
+    // only serve the local machine unless told otherwise
+    var host = options.host || "127.0.0.1";
+    var loopback = isLoopback(host);
+    var server = http.createServer((req, res) => {
+        try {
+            handle(indexes, maxBodySize, loopback, req, res);
+        } catch(e) {
+            // a malformed URL
+            sendError(res, 400, e);
+        }
+    });
+    return new Promise((resolve, reject) => {
+        server.once('error', reject);
+        server.listen(options.port || 0, host, () => {
+            server.removeListener('error', reject);
+            resolve(server);
+        });
+    });
+};
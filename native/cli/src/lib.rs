@@ -0,0 +1,350 @@
+// The `noise` command-line tool, for inspecting and editing indexes without
+// writing a script. Each command opens the index, sends its requests like the
+// Javascript API does and closes the index again. Single values are written as
+// a line of JSON, documents and query results as a line of JSON each. It's a
+// crate of its own, so the Node.js addon doesn't link the line editor of the
+// REPL.
+
+extern crate libc;
+extern crate noise_core;
+extern crate noise_search;
+extern crate rustc_serialize;
+extern crate rustyline;
+
+mod repl;
+
+use std::collections::BTreeMap;
+use std::io::{self, BufRead, Write};
+
+use noise_search::json_value::{JsonValue, PrettyPrint};
+use rustc_serialize::json::Json;
+
+use noise_core::{
+    open_instance, process_message, process_request, query_each, AddOptions, DropOptions,
+    IndexOpenOptions, Message, OpenedIndexCleanupGuard,
+};
+
+pub const USAGE: &str = "Usage: noise <command> <index> [arguments]
+
+Commands:
+    query <index> <query> [--param name=value]...
+                            run the query, one result per line. The parameter
+                            values are JSON, or else strings
+    get <index> <id>        the document with the id
+    add <index>             add the documents from stdin, a JSON document, an
+                            array of them or one per line. Returns the ids
+    delete <index> <id>...  delete the documents, returns whether each was
+                            deleted
+    stats <index>           the statistics of the index
+    export <index>          all documents, one per line
+    import <index>          add the documents from stdin, one per line, creating
+                            the index if needed
+    drop <index> [--dry-run] [--force]
+                            delete the index
+    compact <index>         reclaim the disk space of deleted documents
+    repl <index>            run queries interactively, see `.help` in it
+";
+
+// the number of documents `import` adds at once
+const IMPORT_BATCH_SIZE: usize = 1000;
+
+struct Args {
+    positional: Vec<String>,
+    // the flags given, without their values
+    flags: Vec<String>,
+    params: BTreeMap<String, Json>,
+}
+
+impl Args {
+    fn parse(args: &[String]) -> Result<Args, String> {
+        let mut parsed = Args {
+            positional: Vec::new(),
+            flags: Vec::new(),
+            params: BTreeMap::new(),
+        };
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--param" => {
+                    let param = match args.next() {
+                        Some(param) => param,
+                        None => return Err("--param needs a name=value".to_string()),
+                    };
+                    let (name, value) = match param.find('=') {
+                        Some(pos) => (&param[..pos], &param[pos + 1..]),
+                        None => return Err(format!("--param '{}' isn't name=value", param)),
+                    };
+                    parsed.params.insert(name.to_string(), param_value(value));
+                    parsed.flags.push(arg.clone());
+                }
+                "--dry-run" | "--force" => parsed.flags.push(arg.clone()),
+                flag if flag.starts_with("--") => {
+                    return Err(format!("Unknown option '{}'", flag));
+                }
+                _ => parsed.positional.push(arg.clone()),
+            }
+        }
+        Ok(parsed)
+    }
+
+    // Checks the number of positional arguments and that only the flags the
+    // command takes are given.
+    fn check(&self, min: usize, max: Option<usize>, flags: &[&str]) -> Result<(), String> {
+        if self.positional.len() < min || max.is_some_and(|max| self.positional.len() > max) {
+            return Err(format!("Wrong number of arguments\n\n{}", USAGE));
+        }
+        match self
+            .flags
+            .iter()
+            .find(|flag| !flags.contains(&flag.as_str()))
+        {
+            Some(flag) => Err(format!("Option '{}' isn't supported by this command", flag)),
+            None => Ok(()),
+        }
+    }
+
+    fn has_flag(&self, flag: &str) -> bool {
+        self.flags.iter().any(|given| given == flag)
+    }
+}
+
+// Runs the command given by `args`, without the program name. Documents are
+// read from `input`, the results are written to `output`.
+pub fn run<R: BufRead, W: Write>(args: &[String], input: R, mut output: W) -> Result<(), String> {
+    let command = match args.first() {
+        Some(command) => command.as_str(),
+        None => return Err(USAGE.to_string()),
+    };
+    if command == "help" || command == "--help" {
+        return output
+            .write_all(USAGE.as_bytes())
+            .map_err(|err| err.to_string());
+    }
+    let args = Args::parse(&args[1..])?;
+    match command {
+        "query" => {
+            args.check(2, Some(2), &["--param"])?;
+            let params = if args.params.is_empty() {
+                None
+            } else {
+                Some(Json::Object(args.params.clone()).to_string())
+            };
+            let index = open(&args.positional[0], false, true)?;
+            query_each(&index, &args.positional[1], params, |result| {
+                write_json(&mut output, &result)
+            })
+        }
+        "get" => {
+            args.check(2, Some(2), &[])?;
+            let mut index = open(&args.positional[0], false, true)?;
+            let mut params = BTreeMap::new();
+            params.insert("id".to_string(), Json::String(args.positional[1].clone()));
+            let query_str = "find {_id: == @id} return .".to_string();
+            let params = Json::Object(params).to_string();
+            match query(&mut index, query_str, Some(params))?.pop() {
+                Some(doc) => write_json(&mut output, &doc),
+                None => Err(format!("Document '{}' not found", args.positional[1])),
+            }
+        }
+        "add" => {
+            args.check(1, Some(1), &[])?;
+            let docs = read_docs(input)?;
+            let mut index = open(&args.positional[0], false, false)?;
+            let ids = request(
+                &mut index,
+                Message::Add(docs, AddOptions { percolate: false }),
+            )?;
+            write_json(&mut output, &ids)
+        }
+        "delete" => {
+            args.check(2, None, &[])?;
+            let mut index = open(&args.positional[0], false, false)?;
+            let ids = args.positional[1..].to_vec();
+            let deleted = request(&mut index, Message::Delete(ids))?;
+            write_json(&mut output, &deleted)
+        }
+        "stats" => {
+            args.check(1, Some(1), &[])?;
+            let mut index = open(&args.positional[0], false, true)?;
+            let info = request(&mut index, Message::Info)?;
+            write_json(&mut output, &info)
+        }
+        "export" => {
+            args.check(1, Some(1), &[])?;
+            let index = open(&args.positional[0], false, true)?;
+            // written as they're read, an index may not fit into memory
+            query_each(&index, "find {} return .", None, |doc| {
+                write_json(&mut output, &doc)
+            })
+        }
+        "import" => {
+            args.check(1, Some(1), &[])?;
+            let mut index = open(&args.positional[0], true, false)?;
+            let summary = import(&mut index, input)?;
+            write_json(&mut output, &summary)
+        }
+        "drop" => {
+            args.check(1, Some(1), &["--dry-run", "--force"])?;
+            let options = DropOptions {
+                dry_run: args.has_flag("--dry-run"),
+                force: args.has_flag("--force"),
+            };
+            let name = args.positional[0].clone();
+            let result = response(process_request(Message::DropIndex(name, options)))?;
+            write_json(&mut output, &result)
+        }
+        "compact" => {
+            args.check(1, Some(1), &[])?;
+            let mut index = open(&args.positional[0], false, false)?;
+            let sizes = request(&mut index, Message::Compact)?;
+            write_json(&mut output, &sizes)
+        }
+        "repl" => {
+            args.check(1, Some(1), &[])?;
+            let index = open(&args.positional[0], false, true)?;
+            repl::run(index, input, output)
+        }
+        _ => Err(format!("Unknown command '{}'\n\n{}", command, USAGE)),
+    }
+}
+
+// The value of a query parameter given as text. `n=1` is a number, `n=a` the
+// string "a".
+pub(crate) fn param_value(text: &str) -> Json {
+    match Json::from_str(text) {
+        Ok(json) => json,
+        Err(_) => Json::String(text.to_string()),
+    }
+}
+
+pub(crate) fn open(
+    name: &str,
+    create: bool,
+    read_only: bool,
+) -> Result<OpenedIndexCleanupGuard, String> {
+    let options = IndexOpenOptions {
+        create_if_missing: create,
+        read_only,
+        ..Default::default()
+    };
+    open_instance(name, &options)
+}
+
+pub(crate) fn request(
+    index: &mut OpenedIndexCleanupGuard,
+    msg: Message,
+) -> Result<JsonValue, String> {
+    response(process_message(index, msg))
+}
+
+fn response(msg: Message) -> Result<JsonValue, String> {
+    match msg {
+        Message::ResponseOk(json) => Ok(json),
+        Message::ResponseError(msg) => Err(msg),
+        _ => panic!("Non-response message"),
+    }
+}
+
+pub(crate) fn query(
+    index: &mut OpenedIndexCleanupGuard,
+    query: String,
+    params: Option<String>,
+) -> Result<Vec<JsonValue>, String> {
+    match request(index, Message::Query(query, params))? {
+        // the results come reversed, for the Javascript iterator to pop them
+        JsonValue::Array(mut results) => {
+            results.reverse();
+            Ok(results)
+        }
+        _ => panic!("Query results aren't an array"),
+    }
+}
+
+// Reads the documents for `add`: a document, an array of them or one per line.
+fn read_docs<R: BufRead>(mut input: R) -> Result<Vec<String>, String> {
+    let mut text = String::new();
+    input
+        .read_to_string(&mut text)
+        .map_err(|err| err.to_string())?;
+    match Json::from_str(&text) {
+        Ok(Json::Array(docs)) => return Ok(docs.iter().map(|doc| doc.to_string()).collect()),
+        Ok(doc) => return Ok(vec![doc.to_string()]),
+        Err(_) => (),
+    }
+    let mut docs = Vec::new();
+    for (num, line) in text.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        match Json::from_str(line) {
+            Ok(doc) => docs.push(doc.to_string()),
+            Err(err) => return Err(format!("Invalid JSON on line {}: {}", num + 1, err)),
+        }
+    }
+    Ok(docs)
+}
+
+// Adds the documents of `input`, one per line, in batches. Lines that aren't
+// JSON or can't be added are reported with their line number rather than
+// stopping the import.
+fn import<R: BufRead>(index: &mut OpenedIndexCleanupGuard, input: R) -> Result<JsonValue, String> {
+    let mut imported = 0;
+    let mut errors = Vec::new();
+    let mut batch = Vec::with_capacity(IMPORT_BATCH_SIZE);
+    // the line numbers of the documents in the batch
+    let mut batch_lines = Vec::with_capacity(IMPORT_BATCH_SIZE);
+    let mut lines = input.lines().enumerate().peekable();
+    while let Some((num, line)) = lines.next() {
+        let line = line.map_err(|err| err.to_string())?;
+        if !line.trim().is_empty() {
+            match Json::from_str(&line) {
+                Ok(doc) => {
+                    batch.push(doc.to_string());
+                    batch_lines.push(num + 1);
+                }
+                Err(err) => errors.push(import_error(num + 1, format!("Invalid JSON: {}", err))),
+            }
+        }
+        if batch.len() == IMPORT_BATCH_SIZE || (lines.peek().is_none() && !batch.is_empty()) {
+            let docs = std::mem::replace(&mut batch, Vec::with_capacity(IMPORT_BATCH_SIZE));
+            let results = request(index, Message::Add(docs, AddOptions { percolate: false }))?;
+            if let JsonValue::Array(results) = results {
+                for (result, line) in results.into_iter().zip(batch_lines.drain(..)) {
+                    match result {
+                        JsonValue::Object(mut error) => match error.pop() {
+                            Some((_, JsonValue::String(reason))) => {
+                                errors.push(import_error(line, reason))
+                            }
+                            _ => errors.push(import_error(line, "failed".to_string())),
+                        },
+                        _ => imported += 1,
+                    }
+                }
+            }
+        }
+    }
+    Ok(JsonValue::Object(vec![
+        ("imported".to_string(), JsonValue::Number(imported as f64)),
+        ("errors".to_string(), JsonValue::Array(errors)),
+    ]))
+}
+
+fn import_error(line: usize, reason: String) -> JsonValue {
+    JsonValue::Object(vec![
+        ("line".to_string(), JsonValue::Number(line as f64)),
+        ("error".to_string(), JsonValue::String(reason)),
+    ])
+}
+
+fn write_json<W: Write>(output: &mut W, json: &JsonValue) -> Result<(), String> {
+    let mut line = Vec::new();
+    json.render(&mut line, &mut PrettyPrint::new("", "", ""))
+        .map_err(|err| err.to_string())?;
+    line.push(b'\n');
+    match output.write_all(&line).and_then(|()| output.flush()) {
+        Ok(()) => Ok(()),
+        // the reader went away, like `head` does after enough lines
+        Err(ref err) if err.kind() == io::ErrorKind::BrokenPipe => Ok(()),
+        Err(err) => Err(err.to_string()),
+    }
+}
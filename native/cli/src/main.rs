@@ -0,0 +1,17 @@
+// The `noise` command-line tool, see `lib.rs` for the commands.
+
+extern crate noise_cli;
+
+use std::env;
+use std::io;
+use std::process;
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let stdin = io::stdin();
+    let stdout = io::stdout();
+    if let Err(err) = noise_cli::run(&args, stdin.lock(), stdout.lock()) {
+        eprintln!("noise: {}", err);
+        process::exit(1);
+    }
+}
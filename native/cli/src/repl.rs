@@ -0,0 +1,379 @@
+// `noise repl <index>`: runs queries interactively, see `HELP`. On a terminal
+// the lines are edited with rustyline and kept in `~/.noise_history`.
+
+use std::collections::BTreeMap;
+use std::env;
+use std::io::{self, BufRead, Write};
+use std::mem;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::time::Instant;
+
+use libc;
+use noise_search::json_value::{JsonValue, PrettyPrint};
+use rustc_serialize::json::Json;
+use rustyline::error::ReadlineError;
+use rustyline::{Config, DefaultEditor};
+
+use noise_core::{Message, OpenedIndexCleanupGuard};
+
+use {param_value, request};
+
+const HELP: &str = "Enter a query to run it. A query with a bracket or string still open at
+the end of its first line continues until an empty line, or a line ending
+with `;`.
+
+@name = value   bind a parameter of the queries, the value is JSON, or else a
+                string
+@name           show the value of a parameter
+.params         show all parameters
+.unset name     remove a parameter
+.stats          the statistics of the index
+.help           show this help
+.exit           leave, as does Ctrl-D
+";
+
+const PROMPT: &str = "noise> ";
+const CONTINUATION_PROMPT: &str = "  ...> ";
+
+// the number of entries kept in the history file
+const HISTORY_SIZE: usize = 1000;
+
+enum Input {
+    Line(String),
+    // Ctrl-C, the input so far is thrown away
+    Interrupted,
+    Eof,
+}
+
+// Runs the REPL on the instance until the input ends. Only when both `input`
+// and `output` are the terminal are there prompts and line editing, otherwise
+// the lines are read as they are.
+pub fn run<R: BufRead, W: Write>(
+    mut index: OpenedIndexCleanupGuard,
+    input: R,
+    mut output: W,
+) -> Result<(), String> {
+    let interactive = is_terminal(libc::STDIN_FILENO) && is_terminal(libc::STDOUT_FILENO);
+    let mut editor = LineEditor::new(input, interactive);
+    let mut params = BTreeMap::new();
+    // the lines of a query that isn't complete yet
+    let mut pending = String::new();
+    loop {
+        let prompt = if pending.is_empty() {
+            PROMPT
+        } else {
+            CONTINUATION_PROMPT
+        };
+        let line = match editor.read_line(prompt).map_err(|err| err.to_string())? {
+            Input::Line(line) => line,
+            Input::Interrupted => {
+                pending.clear();
+                continue;
+            }
+            Input::Eof => break,
+        };
+        let output = &mut output;
+        if pending.is_empty() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            } else if line.starts_with('.') {
+                editor.add_history(line);
+                if !command(&mut index, &mut params, line, output)? {
+                    break;
+                }
+                continue;
+            } else if let Some(binding) = line.strip_prefix('@') {
+                editor.add_history(line);
+                bind(&mut params, binding, output)?;
+                continue;
+            }
+        }
+        // a query that fits on the first line runs right away, one that
+        // spans several runs on an empty line or one ending with `;`
+        let first_line = pending.is_empty();
+        let line = line.trim_end();
+        let (line, terminated) = match line.strip_suffix(';') {
+            Some(line) => (line, true),
+            None => (line, line.is_empty()),
+        };
+        pending.push_str(line);
+        pending.push('\n');
+        if terminated || (first_line && is_complete(&pending)) {
+            let query = mem::take(&mut pending);
+            editor.add_history(query.trim());
+            run_query(&mut index, &params, query, output, interactive)?;
+        }
+    }
+    if interactive {
+        // leave the shell prompt on a line of its own
+        write_out(&mut output, "\n")?;
+    }
+    Ok(())
+}
+
+// Runs a `.` command. Returns false if the REPL should end.
+fn command<W: Write>(
+    index: &mut OpenedIndexCleanupGuard,
+    params: &mut BTreeMap<String, Json>,
+    line: &str,
+    output: &mut W,
+) -> Result<bool, String> {
+    let mut words = line.split_whitespace();
+    match (words.next(), words.next(), words.next()) {
+        (Some(".exit"), None, _) | (Some(".quit"), None, _) => return Ok(false),
+        (Some(".help"), None, _) => write_out(output, HELP)?,
+        (Some(".params"), None, _) => {
+            for (name, value) in params.iter() {
+                write_out(output, &format!("@{} = {}\n", name, value))?;
+            }
+        }
+        (Some(".unset"), Some(name), None) => {
+            let name = name.trim_start_matches('@');
+            if params.remove(name).is_none() {
+                print_error(&format!("@{} isn't set", name));
+            }
+        }
+        (Some(".stats"), None, _) => match request(index, Message::Info) {
+            Ok(info) => write_out(output, &pretty(&info)?)?,
+            Err(reason) => print_error(&reason),
+        },
+        _ => print_error(&format!("Unknown command '{}', see .help", line)),
+    }
+    Ok(true)
+}
+
+// Handles `@name = value` and `@name`, without the `@`.
+fn bind<W: Write>(
+    params: &mut BTreeMap<String, Json>,
+    binding: &str,
+    output: &mut W,
+) -> Result<(), String> {
+    let (name, value) = match binding.find('=') {
+        Some(pos) => (binding[..pos].trim(), Some(binding[pos + 1..].trim())),
+        None => (binding.trim(), None),
+    };
+    let valid = name.chars().all(|c| c.is_alphanumeric() || c == '_')
+        && !name.starts_with(|c: char| c.is_ascii_digit());
+    if name.is_empty() || !valid {
+        print_error(&format!("'{}' isn't a parameter name", name));
+        return Ok(());
+    }
+    match value {
+        Some(value) => {
+            params.insert(name.to_string(), param_value(value));
+        }
+        None => match params.get(name) {
+            Some(value) => write_out(output, &format!("{}\n", value))?,
+            None => print_error(&format!("@{} isn't set", name)),
+        },
+    }
+    Ok(())
+}
+
+fn run_query<W: Write>(
+    index: &mut OpenedIndexCleanupGuard,
+    params: &BTreeMap<String, Json>,
+    query: String,
+    output: &mut W,
+    interactive: bool,
+) -> Result<(), String> {
+    let params = if params.is_empty() {
+        None
+    } else {
+        Some(Json::Object(params.clone()).to_string())
+    };
+    let start = Instant::now();
+    let results = match ::query(index, query, params) {
+        Ok(results) => results,
+        Err(reason) => {
+            print_error(&reason);
+            return Ok(());
+        }
+    };
+    let elapsed = start.elapsed();
+    let mut text = String::new();
+    for result in &results {
+        text.push_str(&pretty(result)?);
+    }
+    // more lines than fit on the terminal are shown in the pager
+    let rows = if interactive { terminal_rows() } else { None };
+    match rows {
+        Some(rows) if text.lines().count() >= rows => {
+            output.flush().map_err(|err| err.to_string())?;
+            if !page(&text) {
+                write_out(output, &text)?;
+            }
+        }
+        _ => write_out(output, &text)?,
+    }
+    write_out(
+        output,
+        &format!(
+            "{} result{} ({:.1} ms)\n",
+            results.len(),
+            if results.len() == 1 { "" } else { "s" },
+            elapsed.as_secs_f64() * 1000.0
+        ),
+    )
+}
+
+// Whether the query has no open brackets or strings left.
+fn is_complete(query: &str) -> bool {
+    let mut depth = 0;
+    let mut in_string = false;
+    let mut escaped = false;
+    for c in query.chars() {
+        if in_string {
+            if escaped {
+                escaped = false;
+            } else if c == '\\' {
+                escaped = true;
+            } else if c == '"' {
+                in_string = false;
+            }
+            continue;
+        }
+        match c {
+            '"' => in_string = true,
+            '{' | '[' | '(' => depth += 1,
+            '}' | ']' | ')' => depth -= 1,
+            _ => (),
+        }
+    }
+    !in_string && depth <= 0
+}
+
+fn pretty(json: &JsonValue) -> Result<String, String> {
+    let mut text = Vec::new();
+    json.render(&mut text, &mut PrettyPrint::new("  ", "\n", " "))
+        .map_err(|err| err.to_string())?;
+    text.push(b'\n');
+    String::from_utf8(text).map_err(|err| err.to_string())
+}
+
+// Shows the text in `$PAGER`, or `less`. Returns false if there's no pager.
+fn page(text: &str) -> bool {
+    let pager = env::var("PAGER").unwrap_or_else(|_| "less".to_string());
+    let mut command = Command::new("sh");
+    command.arg("-c").arg(&pager).stdin(Stdio::piped());
+    if env::var_os("LESS").is_none() {
+        // quit at the end and keep the results on the screen
+        command.env("LESS", "FRX");
+    }
+    let mut child = match command.spawn() {
+        Ok(child) => child,
+        Err(_) => return false,
+    };
+    if let Some(mut stdin) = child.stdin.take() {
+        // the pager may be quit before it read everything
+        let _ = stdin.write_all(text.as_bytes());
+    }
+    child.wait().is_ok()
+}
+
+fn write_out<W: Write>(output: &mut W, text: &str) -> Result<(), String> {
+    output
+        .write_all(text.as_bytes())
+        .and_then(|()| output.flush())
+        .map_err(|err| err.to_string())
+}
+
+fn print_error(reason: &str) {
+    eprintln!("Error: {}", reason);
+}
+
+fn is_terminal(fd: libc::c_int) -> bool {
+    unsafe { libc::isatty(fd) == 1 }
+}
+
+fn terminal_rows() -> Option<usize> {
+    let mut size: libc::winsize = unsafe { mem::zeroed() };
+    match unsafe { libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut size) } {
+        0 if size.ws_row > 0 => Some(size.ws_row as usize),
+        _ => None,
+    }
+}
+
+// Reads the lines, with rustyline if they're typed on a terminal.
+struct LineEditor<R> {
+    input: R,
+    terminal: Option<DefaultEditor>,
+    // where the history is kept between sessions, if it is
+    history_path: Option<PathBuf>,
+}
+
+impl<R: BufRead> LineEditor<R> {
+    fn new(input: R, interactive: bool) -> LineEditor<R> {
+        let config = Config::builder()
+            .max_history_size(HISTORY_SIZE)
+            .map(|builder| builder.auto_add_history(false).build());
+        let mut terminal = match config {
+            Ok(config) if interactive => DefaultEditor::with_config(config).ok(),
+            _ => None,
+        };
+        // only what's typed goes into the history file, not piped input
+        let history_path = match (env::var_os("HOME"), terminal.as_mut()) {
+            (Some(home), Some(editor)) => {
+                let path = PathBuf::from(home).join(".noise_history");
+                let _ = editor.load_history(&path);
+                Some(path)
+            }
+            _ => None,
+        };
+        LineEditor {
+            input,
+            terminal,
+            history_path,
+        }
+    }
+
+    fn read_line(&mut self, prompt: &str) -> io::Result<Input> {
+        let editor = match self.terminal {
+            Some(ref mut editor) => editor,
+            None => {
+                let mut line = String::new();
+                return match self.input.read_line(&mut line)? {
+                    0 => Ok(Input::Eof),
+                    _ => Ok(Input::Line(
+                        line.trim_end_matches(&['\r', '\n'][..]).to_string(),
+                    )),
+                };
+            }
+        };
+        match editor.readline(prompt) {
+            Ok(line) => Ok(Input::Line(line)),
+            Err(ReadlineError::Interrupted) => Ok(Input::Interrupted),
+            Err(ReadlineError::Eof) => Ok(Input::Eof),
+            Err(ReadlineError::Io(err)) => Err(err),
+            Err(err) => Err(io::Error::other(err.to_string())),
+        }
+    }
+
+    fn add_history(&mut self, entry: &str) {
+        if let Some(ref mut editor) = self.terminal {
+            // multi-line queries are kept on one line
+            let entry = entry.lines().collect::<Vec<_>>().join(" ");
+            let _ = editor.add_history_entry(entry);
+            if let Some(ref path) = self.history_path {
+                // the history is a convenience, failing to save it is no error
+                let _ = editor.save_history(path);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::is_complete;
+
+    #[test]
+    fn complete_queries() {
+        assert!(is_complete("find {foo: == \"bar\"}"));
+        assert!(!is_complete("find {foo: == \"bar\""));
+        assert!(!is_complete("find {foo: == \"}\""));
+        assert!(is_complete("find {foo: == \"\\\"}\"}"));
+        assert!(!is_complete("find {foo: ["));
+    }
+}
@@ -0,0 +1,41 @@
+extern crate noise_cli;
+
+use std::fs;
+use std::path::Path;
+
+// A path for the index `name` that doesn't exist yet.
+fn index_path(name: &str) -> String {
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("cli");
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join(name);
+    let _ = fs::remove_dir_all(&path);
+    path.to_string_lossy().into_owned()
+}
+
+fn run(args: &[&str], input: &[u8]) -> Result<String, String> {
+    let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+    let mut output = Vec::new();
+    noise_cli::run(&args, input, &mut output).map(|()| String::from_utf8(output).unwrap())
+}
+
+#[test]
+fn export_and_import() {
+    let src = index_path("export");
+    let dest = index_path("import");
+    let docs = "{\"_id\": \"a\", \"n\": 1}\n{\"_id\": \"b\", \"n\": 2}\n";
+    run(&["import", &src], docs.as_bytes()).unwrap();
+    let exported = run(&["export", &src], b"").unwrap();
+    assert_eq!(
+        exported,
+        "{\"_id\":\"a\",\"n\":1}\n{\"_id\":\"b\",\"n\":2}\n"
+    );
+    assert_eq!(
+        run(&["import", &dest], exported.as_bytes()).unwrap(),
+        "{\"imported\":2,\"errors\":[]}\n"
+    );
+    assert_eq!(
+        run(&["query", &dest, "find {n: == @n}", "--param", "n=2"], b"").unwrap(),
+        "\"b\"\n"
+    );
+    assert!(run(&["get", &dest, "x"], b"").is_err());
+}
@@ -0,0 +1,225 @@
+// Backups of indexes are done with the RocksDB backup engine. Creating a backup
+// goes through the wrapper noise is built on, but it doesn't expose restoring
+// or inspecting backups, so those use the C API directly.
+
+use std::ffi::{CStr, CString};
+use std::fs;
+use std::io;
+use std::os::raw::{c_char, c_void};
+use std::path::Path;
+use std::ptr;
+
+use librocksdb_sys as ffi;
+use noise_search::index::Index;
+use noise_search::json_value::JsonValue;
+use rocksdb::backup::{BackupEngine, BackupEngineOptions};
+
+pub struct BackupInfo {
+    pub id: u32,
+    // seconds since the epoch
+    pub timestamp: i64,
+    pub size: u64,
+    pub files: u32,
+}
+
+impl BackupInfo {
+    pub fn to_json(&self) -> JsonValue {
+        JsonValue::Object(vec![
+            ("backupId".to_string(), JsonValue::Number(self.id as f64)),
+            (
+                "timestamp".to_string(),
+                JsonValue::Number(self.timestamp as f64 * 1000.0),
+            ),
+            ("size".to_string(), JsonValue::Number(self.size as f64)),
+            ("files".to_string(), JsonValue::Number(self.files as f64)),
+        ])
+    }
+}
+
+// Backs up the index into `backup_dir` and returns the new backup. Writes to
+// the index can continue while the backup is taken. Only files that aren't
+// part of an earlier backup in `backup_dir` are copied. With `keep` only that
+// many of the newest backups are kept afterwards, otherwise none are removed.
+pub fn create_backup(
+    index: &Index,
+    backup_dir: &str,
+    keep: Option<u32>,
+) -> Result<BackupInfo, String> {
+    if keep == Some(0) {
+        return Err("At least one backup must be kept".to_string());
+    }
+    {
+        let mut engine = BackupEngine::open(&BackupEngineOptions::default(), backup_dir)
+            .map_err(|err| err.to_string())?;
+        engine
+            .create_new_backup(&index.rocks)
+            .map_err(|err| err.to_string())?;
+        if let Some(keep) = keep {
+            engine
+                .purge_old_backups(keep as usize)
+                .map_err(|err| err.to_string())?;
+        }
+    }
+    match RawBackupEngine::open(backup_dir)?.backups().pop() {
+        Some(info) => Ok(info),
+        None => Err(format!("Backup in '{}' is missing", backup_dir)),
+    }
+}
+
+// Returns all backups in `backup_dir`, oldest first.
+pub fn list_backups(backup_dir: &str) -> Result<Vec<BackupInfo>, String> {
+    check_backup_dir(backup_dir)?;
+    Ok(RawBackupEngine::open(backup_dir)?.backups())
+}
+
+// Restores a backup in `backup_dir` into the index directory `name`, replacing
+// what is there. Without a `backup_id` the latest backup is restored.
+pub fn restore_backup(backup_dir: &str, name: &str, backup_id: Option<u32>) -> Result<(), String> {
+    check_backup_dir(backup_dir)?;
+    let engine = RawBackupEngine::open(backup_dir)?;
+    let backups = engine.backups();
+    let backup_id = match backup_id {
+        None => return engine.restore_latest(name),
+        Some(backup_id) => backup_id,
+    };
+    if !backups.iter().any(|backup| backup.id == backup_id) {
+        return Err(format!("There is no backup with id {}", backup_id));
+    }
+    if backups.last().map(|backup| backup.id) == Some(backup_id) {
+        return engine.restore_latest(name);
+    }
+    drop(engine);
+
+    // The C API can only restore the latest backup. So build a view of the
+    // backup directory that only contains the requested backup, with hard
+    // links to the backup files, and restore the latest backup from there.
+    let view = Path::new(backup_dir).join(format!("restore-{}", backup_id));
+    let result = link_backup(Path::new(backup_dir), &view, backup_id)
+        .map_err(|err| err.to_string())
+        .and_then(|()| {
+            let view = view.to_string_lossy();
+            RawBackupEngine::open(&view)?.restore_latest(name)
+        });
+    let _ = fs::remove_dir_all(&view);
+    result
+}
+
+fn check_backup_dir(backup_dir: &str) -> Result<(), String> {
+    if Path::new(backup_dir).join("meta").is_dir() {
+        Ok(())
+    } else {
+        Err(format!("'{}' doesn't contain any backups", backup_dir))
+    }
+}
+
+// Links the files backup `backup_id` consists of from `backup_dir` into `view`.
+fn link_backup(backup_dir: &Path, view: &Path, backup_id: u32) -> io::Result<()> {
+    let _ = fs::remove_dir_all(view);
+    let id = backup_id.to_string();
+    fs::create_dir_all(view.join("meta"))?;
+    fs::hard_link(
+        backup_dir.join("meta").join(&id),
+        view.join("meta").join(&id),
+    )?;
+    let private = Path::new("private").join(&id);
+    link_dir(&backup_dir.join(&private), &view.join(&private))?;
+    for shared in &["shared", "shared_checksum"] {
+        if backup_dir.join(shared).is_dir() {
+            link_dir(&backup_dir.join(shared), &view.join(shared))?;
+        }
+    }
+    Ok(())
+}
+
+fn link_dir(from: &Path, to: &Path) -> io::Result<()> {
+    fs::create_dir_all(to)?;
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        if entry.file_type()?.is_file() {
+            fs::hard_link(entry.path(), to.join(entry.file_name()))?;
+        }
+    }
+    Ok(())
+}
+
+struct RawBackupEngine {
+    inner: *mut ffi::rocksdb_backup_engine_t,
+}
+
+impl RawBackupEngine {
+    fn open(backup_dir: &str) -> Result<RawBackupEngine, String> {
+        let c_dir = to_cstring(backup_dir)?;
+        let mut err: *mut c_char = ptr::null_mut();
+        let inner = unsafe {
+            let options = ffi::rocksdb_options_create();
+            let inner = ffi::rocksdb_backup_engine_open(options, c_dir.as_ptr(), &mut err);
+            ffi::rocksdb_options_destroy(options);
+            inner
+        };
+        check_error(err)?;
+        if inner.is_null() {
+            return Err("Could not initialize backup engine".to_string());
+        }
+        Ok(RawBackupEngine { inner })
+    }
+
+    // All backups, oldest first.
+    fn backups(&self) -> Vec<BackupInfo> {
+        let mut backups = Vec::new();
+        unsafe {
+            let info = ffi::rocksdb_backup_engine_get_backup_info(self.inner);
+            for i in 0..ffi::rocksdb_backup_engine_info_count(info) {
+                backups.push(BackupInfo {
+                    id: ffi::rocksdb_backup_engine_info_backup_id(info, i),
+                    timestamp: ffi::rocksdb_backup_engine_info_timestamp(info, i),
+                    size: ffi::rocksdb_backup_engine_info_size(info, i),
+                    files: ffi::rocksdb_backup_engine_info_number_files(info, i),
+                });
+            }
+            ffi::rocksdb_backup_engine_info_destroy(info);
+        }
+        backups.sort_by_key(|backup| backup.id);
+        backups
+    }
+
+    fn restore_latest(&self, db_dir: &str) -> Result<(), String> {
+        let c_dir = to_cstring(db_dir)?;
+        let mut err: *mut c_char = ptr::null_mut();
+        unsafe {
+            let options = ffi::rocksdb_restore_options_create();
+            ffi::rocksdb_backup_engine_restore_db_from_latest_backup(
+                self.inner,
+                c_dir.as_ptr(),
+                c_dir.as_ptr(),
+                options,
+                &mut err,
+            );
+            ffi::rocksdb_restore_options_destroy(options);
+        }
+        check_error(err)
+    }
+}
+
+impl Drop for RawBackupEngine {
+    fn drop(&mut self) {
+        unsafe {
+            ffi::rocksdb_backup_engine_close(self.inner);
+        }
+    }
+}
+
+fn to_cstring(path: &str) -> Result<CString, String> {
+    CString::new(path).map_err(|_| format!("Invalid path '{}'", path))
+}
+
+fn check_error(err: *mut c_char) -> Result<(), String> {
+    if err.is_null() {
+        return Ok(());
+    }
+    let message = unsafe {
+        let message = CStr::from_ptr(err).to_string_lossy().into_owned();
+        ffi::rocksdb_free(err as *mut c_void);
+        message
+    };
+    Err(message)
+}
@@ -0,0 +1,16 @@
+// Serves JSON-RPC 2.0 requests for noise indexes on stdin and stdout, see
+// `rpc.rs` for the methods.
+
+extern crate noise_core;
+
+use std::io;
+use std::process;
+
+fn main() {
+    let stdin = io::stdin();
+    let stdout = io::stdout();
+    if let Err(err) = noise_core::rpc::serve(stdin.lock(), stdout.lock()) {
+        eprintln!("noise-rpc: {}", err);
+        process::exit(1);
+    }
+}
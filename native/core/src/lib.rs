@@ -0,0 +1,1895 @@
+// The index serving behind the Node.js binding, without anything that needs
+// Node.js. Instances of indexes are opened in this process and served on their
+// own threads, to clients connected over a socket (see `wire`) or called
+// directly, like the `noise` and `noise-rpc` binaries do (see `native/cli` and
+// `rpc`).
+// Each request is a `Message`, answered by a `ResponseOk` or `ResponseError`.
+
+extern crate libc;
+extern crate librocksdb_sys;
+extern crate noise_search;
+extern crate rocksdb;
+extern crate rustc_serialize;
+extern crate unix_socket;
+#[macro_use]
+extern crate lazy_static;
+
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::env;
+use std::ffi::CString;
+use std::fs;
+use std::io::{self, BufReader, Read};
+use std::mem::{self, drop};
+use std::net::Shutdown;
+use std::ops::Deref;
+use std::ops::DerefMut;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{symlink, DirBuilderExt, PermissionsExt};
+use std::panic;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::str;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, Once};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::vec::Vec;
+
+mod backup;
+pub mod rpc;
+pub mod wire;
+
+use unix_socket::{UnixListener, UnixStream};
+
+use noise_search::index::{Batch, Index, MvccRwLock, OpenOptions};
+pub use noise_search::json_value::JsonValue;
+use rustc_serialize::json::Json;
+
+pub enum Message {
+    OpenIndex(String, IndexOpenOptions),
+    DropIndex(String, DropOptions),
+    Add(Vec<String>, AddOptions),
+    Delete(Vec<String>),
+    Query(String, Option<String>),
+    RegisterQuery(String, String, Option<String>),
+    UnregisterQuery(String),
+    Info,
+    Schema(Option<usize>),
+    Backup(String, Option<u32>),
+    Restore(String, String, Option<u32>),
+    ListBackups(String),
+    MemoryUsage,
+    Compact,
+    ListIndexes(String),
+    CopyIndex(String, String),
+    RenameIndex(String, String),
+    Close,
+    ResponseOk(JsonValue),
+    ResponseError(String),
+}
+
+#[derive(Default)]
+pub struct IndexOpenOptions {
+    pub create_if_missing: bool,
+    pub error_if_exists: bool,
+    // the instance rejects any changes to the index
+    pub read_only: bool,
+    // compact the index in the background once this many documents were deleted
+    pub compact_deleted_threshold: Option<usize>,
+    // keep the index in memory, it is gone once the last instance is closed
+    pub in_memory: bool,
+    // drop the index once the last instance is closed or the process exits
+    pub temp: bool,
+}
+
+#[derive(Default)]
+pub struct DropOptions {
+    // only report what would be deleted
+    pub dry_run: bool,
+    // close all open instances of the index first
+    pub force: bool,
+}
+
+#[derive(Default)]
+pub struct AddOptions {
+    // when set, each added document is checked against the registered queries
+    pub percolate: bool,
+}
+
+pub struct OpenedIndex {
+    index: Index,
+    // the name the index is known by in `OPEN_INSTANCES`. For indexes on disk
+    // it's the same as the name of the index.
+    name: String,
+    // can be read and changed without holding the write lock. It's only
+    // changed while holding the lock of `OPEN_INSTANCES` too.
+    open_count: AtomicUsize,
+    // queries registered for percolation, keyed by name. Only touch this while
+    // holding the write lock, as readers don't synchronize with each other.
+    registered_queries: HashMap<String, RegisteredQuery>,
+    // created once the first query is registered
+    percolator: Option<Percolator>,
+    // when a batch was last written to the index by this process, in
+    // milliseconds since the epoch. 0 if it wasn't written to yet.
+    last_flush: AtomicU64,
+    // documents deleted since the index was last compacted by this process
+    deleted_since_compaction: usize,
+    compact_deleted_threshold: Option<usize>,
+    // set while the index is compacted in the background. The index stays
+    // open until the compaction is done, even if its last instance is closed.
+    compacting: AtomicBool,
+    // the sockets of the connections serving an instance of the index, keyed
+    // by connection id. Used to stop the serving threads on a forced drop.
+    connections: HashMap<u64, UnixStream>,
+    // set once the index is force dropped, it can't be opened again until
+    // the drop is done. Can be read without holding the write lock.
+    dropped: AtomicBool,
+    // the instances opened with `open_instance`. A forced drop can't close
+    // them, only their owners can.
+    direct_instances: AtomicUsize,
+    // the directory of an index that is deleted once it's closed. This must
+    // be the last field, so the index is closed before.
+    _remove_on_close: Option<RemoveOnClose>,
+}
+
+// Locks the index for writing. An instance that panicked while holding the
+// lock doesn't keep the other instances from using the index.
+fn write_lock(opened: &MvccRwLock<OpenedIndex>) -> MutexGuard<'_, Box<OpenedIndex>> {
+    match opened.write() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+fn now_millis() -> u64 {
+    let since_epoch = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    since_epoch.as_secs() * 1000 + since_epoch.subsec_millis() as u64
+}
+
+struct RemoveOnClose(PathBuf);
+
+impl RemoveOnClose {
+    fn new(path: PathBuf) -> RemoveOnClose {
+        REMOVE_ON_EXIT.lock().unwrap().insert(path.clone());
+        RemoveOnClose(path)
+    }
+}
+
+impl Drop for RemoveOnClose {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+        REMOVE_ON_EXIT.lock().unwrap().remove(&self.0);
+    }
+}
+
+// The directories of the open in-memory and temporary indexes, which are
+// removed when the process exits.
+lazy_static! {
+    static ref REMOVE_ON_EXIT: Mutex<HashSet<PathBuf>> = Mutex::new(HashSet::new());
+}
+
+struct RegisteredQuery {
+    // the filter part of the query, without the `find` keyword and any
+    // `order`, `return` or `limit` clauses
+    filter: String,
+    params: Option<String>,
+}
+
+// A scratch index the added documents are matched against the registered
+// queries in, one document at a time.
+struct Percolator {
+    index: Index,
+    // this must be the last field, so the index is closed before
+    _remove_on_close: RemoveOnClose,
+}
+
+impl Percolator {
+    // Returns the percolator in `slot`, creating it first if there is none.
+    fn get(slot: &mut Option<Percolator>) -> Result<&mut Percolator, String> {
+        if slot.is_none() {
+            let path = memory_index_dir();
+            let remove_on_close = RemoveOnClose::new(path.clone());
+            let index = Index::open(&path.to_string_lossy(), Some(OpenOptions::Create))
+                .map_err(|err| err.to_string())?;
+            *slot = Some(Percolator {
+                index,
+                _remove_on_close: remove_on_close,
+            });
+        }
+        Ok(slot.as_mut().unwrap())
+    }
+
+    // Whether the filter matches any document in the scratch index.
+    fn matches(&self, filter: &str, params: Option<String>) -> Result<bool, String> {
+        let query = format!("find {} return ._id limit 1", filter);
+        match self.index.query(&query, params) {
+            Ok(mut results) => Ok(results.next().is_some()),
+            Err(reason) => Err(reason.to_string()),
+        }
+    }
+
+    // Returns the names of the registered queries the document with the id
+    // `id` matches. The document is only in the scratch index meanwhile.
+    fn match_doc(
+        &mut self,
+        doc: &str,
+        id: &str,
+        names: &[&String],
+        registered_queries: &HashMap<String, RegisteredQuery>,
+    ) -> Result<Vec<JsonValue>, String> {
+        // the document gets the id it got in the index, for queries on `_id`
+        let doc = match Json::from_str(doc) {
+            Ok(Json::Object(mut object)) => {
+                object.insert("_id".to_string(), Json::String(id.to_string()));
+                Json::Object(object).to_string()
+            }
+            _ => doc.to_string(),
+        };
+        let mut batch = Batch::new();
+        self.index
+            .add(&doc, &mut batch)
+            .and_then(|_| self.index.flush(batch))
+            .map_err(|reason| reason.to_string())?;
+        let mut matches = Vec::new();
+        for name in names {
+            let registered = &registered_queries[*name];
+            let found = self
+                .matches(&registered.filter, registered.params.clone())
+                .map_err(|reason| format!("Percolating query '{}' failed: {}", name, reason))?;
+            if found {
+                matches.push(JsonValue::String((*name).clone()));
+            }
+        }
+        let mut batch = Batch::new();
+        self.index
+            .delete(id, &mut batch)
+            .and_then(|_| self.index.flush(batch))
+            .map_err(|reason| reason.to_string())?;
+        Ok(matches)
+    }
+}
+
+// An open instance of an index. The index is closed once its last instance is
+// dropped.
+pub struct OpenedIndexCleanupGuard {
+    index: Arc<MvccRwLock<OpenedIndex>>,
+    // the connection serving the instance, if any
+    connection_id: Option<u64>,
+    // the instance rejects all requests that would change the index
+    read_only: bool,
+}
+
+impl Drop for OpenedIndexCleanupGuard {
+    fn drop(&mut self) {
+        match self.connection_id {
+            Some(connection_id) => {
+                write_lock(&self.index).connections.remove(&connection_id);
+            }
+            None => {
+                self.index
+                    .read()
+                    .direct_instances
+                    .fetch_sub(1, Ordering::SeqCst);
+            }
+        }
+        let mut guard = match OPEN_INSTANCES.lock() {
+            Ok(guard) => guard,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        let map = guard.deref_mut();
+        let opened = self.index.read();
+        if opened.open_count.fetch_sub(1, Ordering::SeqCst) == 1
+            && !opened.compacting.load(Ordering::SeqCst)
+        {
+            // the index may already be gone from the map, e.g. after a forced
+            // drop, and another one opened under the same name
+            if map
+                .get(&opened.name)
+                .is_some_and(|entry| Arc::ptr_eq(entry, &self.index))
+            {
+                map.remove(&opened.name);
+            }
+        }
+    }
+}
+
+impl Deref for OpenedIndexCleanupGuard {
+    type Target = Arc<MvccRwLock<OpenedIndex>>;
+
+    fn deref(&self) -> &Arc<MvccRwLock<OpenedIndex>> {
+        &self.index
+    }
+}
+
+impl DerefMut for OpenedIndexCleanupGuard {
+    fn deref_mut(&mut self) -> &mut Arc<MvccRwLock<OpenedIndex>> {
+        &mut self.index
+    }
+}
+
+static LISTENER_STARTED: AtomicBool = AtomicBool::new(false);
+
+// The sockets the daemons of this process listen on.
+lazy_static! {
+    static ref DAEMON_SOCKETS: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+}
+
+// The connection ids are the keys of the response slots of the Node.js
+// binding, so they must be unique across all worker threads.
+static NEXT_CONNECTION_ID: AtomicU64 = AtomicU64::new(0);
+
+// Set once noise is shut down, no more connections are accepted.
+static SHUTTING_DOWN: AtomicBool = AtomicBool::new(false);
+
+// Used to give every in-memory index a unique directory.
+static MEMORY_INDEXES: AtomicUsize = AtomicUsize::new(0);
+
+// When set, indexes outside of this directory can't be dropped.
+lazy_static! {
+    static ref BASE_DIR: Mutex<Option<PathBuf>> = Mutex::new(None);
+}
+
+// This lock only allows one index to be updated at a time.
+lazy_static! {
+    static ref OPEN_INSTANCES: Mutex<HashMap<String, Arc<MvccRwLock<OpenedIndex>>>> =
+        Mutex::new(HashMap::new());
+}
+
+// Starts serving the clients of the Node.js binding on `echo.sock`. The module
+// is loaded by every worker thread, but they all share the listener of the
+// first one.
+pub fn start_listener() -> io::Result<()> {
+    if LISTENER_STARTED.swap(true, Ordering::SeqCst) {
+        return Ok(());
+    }
+    let _ = fs::remove_file("echo.sock");
+    let listener = UnixListener::bind("echo.sock")?;
+    thread::spawn(move || accept_clients(listener));
+    Ok(())
+}
+
+// Starts a daemon serving clients in other processes on the socket at `path`.
+pub fn start_daemon(path: PathBuf) -> Result<(), String> {
+    if SHUTTING_DOWN.load(Ordering::SeqCst) {
+        return Err("noise is shut down".to_string());
+    }
+    // a socket left behind by a daemon that crashed is replaced, but not one
+    // that is in use
+    if UnixStream::connect(&path).is_ok() {
+        return Err(format!(
+            "a daemon is already listening on {}",
+            path.display()
+        ));
+    }
+    let _ = fs::remove_file(&path);
+    let listener = bind_private(&path).map_err(|err| err.to_string())?;
+    DAEMON_SOCKETS.lock().unwrap().push(path);
+    thread::spawn(move || accept_clients(listener));
+    Ok(())
+}
+
+// Creates the socket at `path` so only its owner can connect, with mode 0600.
+// It's bound in a directory only the owner can enter and then moved to
+// `path`, so nobody else can connect before the mode is set.
+fn bind_private(path: &Path) -> io::Result<UnixListener> {
+    let file_name = match path.file_name() {
+        Some(file_name) => file_name.to_string_lossy().into_owned(),
+        None => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("'{}' isn't a file name", path.display()),
+            ))
+        }
+    };
+    let dir = path.with_file_name(format!(".{}-{}", file_name, process::id()));
+    fs::DirBuilder::new().mode(0o700).create(&dir)?;
+    let bind = || -> io::Result<UnixListener> {
+        let tmp = dir.join(&file_name);
+        let listener = UnixListener::bind(&tmp)?;
+        fs::set_permissions(&tmp, fs::Permissions::from_mode(0o600))?;
+        fs::rename(&tmp, path)?;
+        Ok(listener)
+    };
+    let result = bind();
+    let _ = fs::remove_dir_all(&dir);
+    result
+}
+
+// Accepts connections and processes them, spawning a new thread for each one,
+// until noise is shut down.
+fn accept_clients(listener: UnixListener) {
+    for stream in listener.incoming() {
+        if SHUTTING_DOWN.load(Ordering::SeqCst) {
+            break;
+        }
+        match stream {
+            Ok(stream) => {
+                thread::spawn(move || handle_client_outer(stream));
+            }
+            Err(err) => {
+                eprintln!("noise: stopped accepting connections: {}", err);
+                break;
+            }
+        }
+    }
+}
+
+pub fn next_connection_id() -> u64 {
+    NEXT_CONNECTION_ID.fetch_add(1, Ordering::SeqCst)
+}
+
+// Sets the directory indexes must be in to be dropped, None allows any.
+pub fn set_base_dir(base_dir: Option<PathBuf>) {
+    *BASE_DIR.lock().unwrap() = base_dir;
+}
+
+// Opens an instance of the index `name` that is used in this process, rather
+// than served over a socket. Requests are sent with `process_message`.
+pub fn open_instance(
+    name: &str,
+    options: &IndexOpenOptions,
+) -> Result<OpenedIndexCleanupGuard, String> {
+    let index = open_index(name.to_string(), options, next_connection_id(), None)?;
+    index.read().direct_instances.fetch_add(1, Ordering::SeqCst);
+    Ok(OpenedIndexCleanupGuard {
+        index,
+        connection_id: None,
+        read_only: options.read_only,
+    })
+}
+
+fn handle_client_outer(stream: UnixStream) {
+    // the id of the connection among those serving an index
+    let connection_id = next_connection_id();
+    let mut connection = Connection::new(stream);
+
+    // the first message says what the client wants
+    let msg = match connection.receive_any() {
+        Some(msg) => msg,
+        None => return,
+    };
+    match msg {
+        Message::OpenIndex(name, options) => {
+            let read_only = options.read_only;
+            match open_index(name, &options, connection_id, Some(connection.stream())) {
+                Ok(index) => {
+                    connection.respond(Message::ResponseOk(JsonValue::True));
+                    let index_guard = OpenedIndexCleanupGuard {
+                        index,
+                        connection_id: Some(connection_id),
+                        read_only,
+                    };
+                    // now start servicing instance requests. A panic was
+                    // reported by the panic hook already, the instance is
+                    // closed while unwinding.
+                    let _ = panic::catch_unwind(|| {
+                        handle_client(index_guard, connection);
+                    });
+                }
+                Err(msg) => connection.respond(Message::ResponseError(msg)),
+            }
+        }
+        msg => connection.respond(process_request(msg)),
+    }
+}
+
+// Processes a request that isn't sent to an index instance, like dropping an
+// index or listing the backups in a directory.
+pub fn process_request(message: Message) -> Message {
+    match message {
+        Message::DropIndex(name, options) => drop_index(&name, &options),
+        Message::Restore(backup_dir, name, backup_id) => {
+            // keep the lock so the index can't be opened while restoring
+            let mut guard = OPEN_INSTANCES.lock().unwrap();
+            let map = guard.deref_mut();
+            if map.contains_key(&name) {
+                return Message::ResponseError("Index instances still open".to_string());
+            }
+            // the index is replaced, so it must be one that could be dropped
+            if let Err(msg) = check_base_dir(&name).and_then(|()| files_to_drop(&name)) {
+                return Message::ResponseError(msg);
+            }
+            match backup::restore_backup(&backup_dir, &name, backup_id) {
+                Ok(()) => Message::ResponseOk(JsonValue::True),
+                Err(msg) => Message::ResponseError(msg),
+            }
+        }
+        Message::ListBackups(backup_dir) => match backup::list_backups(&backup_dir) {
+            Ok(backups) => Message::ResponseOk(JsonValue::Array(
+                backups.iter().map(|backup| backup.to_json()).collect(),
+            )),
+            Err(msg) => Message::ResponseError(msg),
+        },
+        Message::MemoryUsage => Message::ResponseOk(memory_usage()),
+        Message::CopyIndex(src, dest) => copy_closed_index(&src, &dest, false),
+        Message::RenameIndex(src, dest) => copy_closed_index(&src, &dest, true),
+        Message::ListIndexes(dir) => match list_indexes(Path::new(&dir)) {
+            Ok(indexes) => Message::ResponseOk(JsonValue::Array(indexes)),
+            Err(msg) => Message::ResponseError(msg),
+        },
+        _ => Message::ResponseError("An index must be opened first".to_string()),
+    }
+}
+
+// Deletes the index `name`, which must not be open unless it's dropped by force.
+fn drop_index(name: &str, options: &DropOptions) -> Message {
+    if options.force && !options.dry_run {
+        // nothing but their owners can close the instances opened with
+        // `open_instance`, so they aren't waited for
+        let direct_instances = match OPEN_INSTANCES.lock().unwrap().get(name) {
+            Some(opened) => opened.read().direct_instances.load(Ordering::SeqCst),
+            None => 0,
+        };
+        if direct_instances > 0 {
+            return Message::ResponseError("Index instances still open".to_string());
+        }
+        close_instances(name, Instant::now() + FORCE_DROP_TIMEOUT);
+    }
+    let mut guard = OPEN_INSTANCES.lock().unwrap();
+    let map = guard.deref_mut();
+    if map.contains_key(name) {
+        Message::ResponseError("Index instances still open".to_string())
+    } else {
+        match files_to_drop(name) {
+            Ok(files) if options.dry_run => Message::ResponseOk(dry_run_json(name, files)),
+            Ok(_) => match Index::drop(name) {
+                Ok(()) => Message::ResponseOk(JsonValue::True),
+                Err(msg) => Message::ResponseError(msg.to_string()),
+            },
+            Err(msg) => Message::ResponseError(msg),
+        }
+    }
+}
+
+// How long a forced drop waits for the serving threads to finish the
+// requests they are working on.
+const FORCE_DROP_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Makes the serving threads of all instances of the index `name` stop once
+// they are done with the request they are working on, and waits until the
+// index is closed. The clients get an error for any further requests. Returns
+// false if the index wasn't closed before the deadline.
+fn close_instances(name: &str, deadline: Instant) -> bool {
+    let opened = {
+        let guard = OPEN_INSTANCES.lock().unwrap();
+        let opened = match guard.get(name) {
+            Some(opened) => opened.clone(),
+            None => return true,
+        };
+        {
+            let locked = write_lock(&opened);
+            locked.dropped.store(true, Ordering::SeqCst);
+            // a serving thread waiting for the next request reads the end of
+            // the stream and stops, closing its connection
+            for stream in locked.connections.values() {
+                let _ = stream.shutdown(Shutdown::Read);
+            }
+        }
+        opened
+    };
+    // the index is closed once the last instance removed it from the open
+    // instances and only this reference is left
+    while Arc::strong_count(&opened) > 1 {
+        if Instant::now() >= deadline {
+            return false;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    true
+}
+
+// Stops accepting connections, closes all open indexes and removes the
+// socket. Waits at most `timeout` for the instances to finish their current
+// request. Returns false if some weren't closed in time.
+pub fn shutdown(timeout: Duration) -> bool {
+    let deadline = Instant::now() + timeout;
+    if !SHUTTING_DOWN.swap(true, Ordering::SeqCst) {
+        // wake up the listener, so it notices the shutdown
+        let _ = UnixStream::connect("echo.sock");
+        let _ = fs::remove_file("echo.sock");
+        for path in DAEMON_SOCKETS.lock().unwrap().drain(..) {
+            let _ = UnixStream::connect(&path);
+            let _ = fs::remove_file(path);
+        }
+    }
+    let names: Vec<String> = OPEN_INSTANCES.lock().unwrap().keys().cloned().collect();
+    let mut closed = true;
+    for name in names {
+        closed &= close_instances(&name, deadline);
+    }
+    // whatever couldn't be closed is removed anyway
+    for path in REMOVE_ON_EXIT.lock().unwrap().drain() {
+        let _ = fs::remove_dir_all(path);
+    }
+    closed
+}
+
+// Opens an instance of the index `name`, served over `stream` if it is served
+// over a socket. Opening an index that is already open in this process shares
+// it.
+fn open_index(
+    name: String,
+    options: &IndexOpenOptions,
+    connection_id: u64,
+    stream: Option<&UnixStream>,
+) -> Result<Arc<MvccRwLock<OpenedIndex>>, String> {
+    let mut guard = OPEN_INSTANCES.lock().unwrap();
+    let map = guard.deref_mut();
+    let index_name = name;
+    // in-memory indexes get a name that can't clash with an index on disk,
+    // nor with each other. Each ":memory:" index is a new one. Read-only
+    // instances share the index if it's open in this process, otherwise a view
+    // of it.
+    let name = if options.in_memory {
+        if index_name == ":memory:" {
+            format!(
+                "{}{}",
+                ANONYMOUS_MEMORY_PREFIX,
+                MEMORY_INDEXES.fetch_add(1, Ordering::SeqCst)
+            )
+        } else {
+            format!("{}{}", NAMED_MEMORY_PREFIX, index_name)
+        }
+    } else if options.read_only && !map.contains_key(&index_name) {
+        format!("{}{}", READ_ONLY_PREFIX, index_name)
+    } else {
+        index_name.clone()
+    };
+    match map.get_mut(&name) {
+        Some(_) if options.error_if_exists => Err("Index already exists".to_string()),
+        Some(opened_index) if opened_index.read().dropped.load(Ordering::SeqCst) => {
+            Err("Index is being dropped".to_string())
+        }
+        Some(opened_index) => {
+            {
+                let mut opened = write_lock(opened_index);
+                opened.open_count.fetch_add(1, Ordering::SeqCst);
+                if let Some(Ok(stream)) = stream.map(UnixStream::try_clone) {
+                    opened.connections.insert(connection_id, stream);
+                }
+                if options.compact_deleted_threshold.is_some() {
+                    opened.compact_deleted_threshold = options.compact_deleted_threshold;
+                }
+            }
+            Ok(opened_index.clone())
+        }
+        None if options.error_if_exists
+            && !options.in_memory
+            && Path::new(&index_name).join("CURRENT").exists() =>
+        {
+            Err("Index already exists".to_string())
+        }
+        None => {
+            // a read only instance never creates the index
+            let create = if options.in_memory || (options.create_if_missing && !options.read_only) {
+                Some(OpenOptions::Create)
+            } else {
+                None
+            };
+            // RocksDB would leave a directory with a LOCK and a LOG file behind
+            if create.is_none() && !Path::new(&index_name).join("CURRENT").exists() {
+                return Err(format!("Index '{}' doesn't exist", index_name));
+            }
+            let (path, remove_on_close) = if name.starts_with(READ_ONLY_PREFIX) {
+                let path = link_view(&index_name)?;
+                (
+                    path.to_string_lossy().into_owned(),
+                    Some(RemoveOnClose::new(path)),
+                )
+            } else if options.in_memory {
+                let path = memory_index_dir();
+                (
+                    path.to_string_lossy().into_owned(),
+                    Some(RemoveOnClose::new(path)),
+                )
+            } else if options.temp {
+                remove_stale_temp_indexes(Path::new(&name));
+                (name.clone(), Some(RemoveOnClose::new(PathBuf::from(&name))))
+            } else {
+                (name.clone(), None)
+            };
+            match Index::open(&path, create) {
+                Ok(new_index) => {
+                    let mut connections = HashMap::new();
+                    if let Some(Ok(stream)) = stream.map(UnixStream::try_clone) {
+                        connections.insert(connection_id, stream);
+                    }
+                    let new_index = Arc::new(MvccRwLock::new(OpenedIndex {
+                        index: new_index,
+                        name: name.clone(),
+                        open_count: AtomicUsize::new(1),
+                        registered_queries: HashMap::new(),
+                        percolator: None,
+                        last_flush: AtomicU64::new(0),
+                        deleted_since_compaction: 0,
+                        compact_deleted_threshold: options.compact_deleted_threshold,
+                        compacting: AtomicBool::new(false),
+                        connections,
+                        dropped: AtomicBool::new(false),
+                        direct_instances: AtomicUsize::new(0),
+                        _remove_on_close: remove_on_close,
+                    }));
+                    map.insert(name, new_index.clone());
+                    Ok(new_index)
+                }
+                Err(msg) => {
+                    drop(remove_on_close);
+                    Err(msg.to_string())
+                }
+            }
+        }
+    }
+}
+
+// A connection to a client, in this process or another one. The requests and
+// responses are sent in frames, see `wire`.
+struct Connection {
+    reader: BufReader<UnixStream>,
+    // the id of the request being served, its response gets the same one
+    request_id: u32,
+}
+
+impl Connection {
+    fn new(stream: UnixStream) -> Connection {
+        Connection {
+            reader: BufReader::new(stream),
+            request_id: 0,
+        }
+    }
+
+    fn stream(&self) -> &UnixStream {
+        self.reader.get_ref()
+    }
+
+    // Returns the next message, or None once the client hung up.
+    fn receive_any(&mut self) -> Option<Message> {
+        loop {
+            let frame = match wire::read_frame(&mut self.reader) {
+                Ok(Some(frame)) => frame,
+                Ok(None) => return None,
+                Err(msg) => {
+                    // the frames can't be told apart anymore. Tell the client
+                    // why and hang up.
+                    self.respond(Message::ResponseError(msg.to_string()));
+                    return None;
+                }
+            };
+            self.request_id = frame.request_id;
+            match frame.message() {
+                Ok(Message::ResponseOk(_)) | Ok(Message::ResponseError(_)) => self.respond(
+                    Message::ResponseError("Clients can't send responses".to_string()),
+                ),
+                Ok(msg) => return Some(msg),
+                Err(msg) => self.respond(Message::ResponseError(msg)),
+            }
+        }
+    }
+
+    // Returns the next request for an index instance, or None once the client
+    // hung up.
+    fn receive(&mut self) -> Option<Message> {
+        loop {
+            match self.receive_any() {
+                Some(msg) if is_instance_request(&msg) => return Some(msg),
+                Some(_msg) => self.respond(Message::ResponseError(
+                    "Message can't be sent to an index instance".to_string(),
+                )),
+                None => return None,
+            }
+        }
+    }
+
+    fn respond(&mut self, resp: Message) {
+        // a client that is gone notices nothing, the next receive ends
+        // a response too large for a frame is replaced by the error
+        let frame = wire::Frame::new(self.request_id, &resp)
+            .or_else(|msg| wire::Frame::new(self.request_id, &Message::ResponseError(msg)));
+        if let Ok(frame) = frame {
+            let _ = wire::write_frame(self.reader.get_mut(), &frame);
+        }
+    }
+}
+
+// Whether the message is a request an opened instance serves.
+fn is_instance_request(msg: &Message) -> bool {
+    matches!(
+        *msg,
+        Message::Add(_, _)
+            | Message::Delete(_)
+            | Message::Query(_, _)
+            | Message::RegisterQuery(_, _, _)
+            | Message::UnregisterQuery(_)
+            | Message::Info
+            | Message::Schema(_)
+            | Message::Backup(_, _)
+            | Message::Compact
+            | Message::Close
+    )
+}
+
+// Whether the message is a request that changes the index, or that may write
+// to its storage.
+fn is_change_request(msg: &Message) -> bool {
+    matches!(
+        *msg,
+        Message::Add(_, _)
+            | Message::Delete(_)
+            | Message::RegisterQuery(_, _, _)
+            | Message::UnregisterQuery(_)
+            | Message::Backup(_, _)
+            | Message::Compact
+    )
+}
+
+fn handle_client(mut index: OpenedIndexCleanupGuard, mut connection: Connection) {
+    loop {
+        let msg = match connection.receive() {
+            Some(msg) => msg,
+            None => {
+                // end the connection before closing the instance. A forced
+                // drop waits for the index to be closed, by then the client
+                // must have been told.
+                drop(connection);
+                break;
+            }
+        };
+
+        if let Message::Close = msg {
+            drop(index); // make sure index instance is closed first
+            return; // now we end the loop. The client will notice the socket close.
+        }
+        // process the message
+        let response = process_message(&mut index, msg);
+
+        connection.respond(response);
+    }
+}
+
+// Runs the query and passes each result to `f` as it's read, instead of
+// collecting them like a `Query` request does. Stops at the first error of
+// `f`.
+pub fn query_each<F>(
+    index: &OpenedIndexCleanupGuard,
+    query: &str,
+    params: Option<String>,
+    mut f: F,
+) -> Result<(), String>
+where
+    F: FnMut(JsonValue) -> Result<(), String>,
+{
+    let opened = index.read();
+    let results = opened
+        .index
+        .query(query, params)
+        .map_err(|reason| reason.to_string())?;
+    for result in results {
+        f(result)?;
+    }
+    Ok(())
+}
+
+// Processes a request sent to the index instance and returns the response.
+// Messages that aren't for an instance, which go to `process_request`, and
+// `Close`, as the instance is closed by dropping it, get an error.
+pub fn process_message(index: &mut OpenedIndexCleanupGuard, message: Message) -> Message {
+    if index.read_only && is_change_request(&message) {
+        return Message::ResponseError("Index instance is opened read-only".to_string());
+    }
+    match message {
+        Message::Add(vec, options) => {
+            let mut results = Vec::with_capacity(vec.len());
+            let mut batch = Batch::new();
+            let mut opened = write_lock(index);
+            {
+                let index = &mut opened.index;
+                for doc_str in &vec {
+                    match index.add(doc_str, &mut batch) {
+                        Ok(id) => results.push(JsonValue::String(id)),
+                        Err(reason) => {
+                            let err_str = JsonValue::String(reason.to_string());
+                            let err_obj = vec![("error".to_string(), err_str)];
+                            results.push(JsonValue::Object(err_obj))
+                        }
+                    }
+                }
+                if let Err(reason) = index.flush(batch) {
+                    return Message::ResponseError(reason.to_string());
+                }
+            }
+            opened.last_flush.store(now_millis(), Ordering::SeqCst);
+            if options.percolate {
+                let results = percolate(&mut opened, &vec, results);
+                Message::ResponseOk(JsonValue::Array(results))
+            } else {
+                Message::ResponseOk(JsonValue::Array(results))
+            }
+        }
+        Message::Delete(vec) => {
+            let mut opened = write_lock(index);
+            let mut batch = Batch::new();
+            let mut results = Vec::with_capacity(vec.len());
+            for doc_str in vec {
+                match opened.index.delete(&doc_str, &mut batch) {
+                    Ok(true) => results.push(JsonValue::True),
+                    Ok(false) => results.push(JsonValue::False),
+                    Err(reason) => {
+                        let err_str = JsonValue::String(reason.to_string());
+                        let err_obj = vec![("error".to_string(), err_str)];
+                        results.push(JsonValue::Object(err_obj))
+                    }
+                }
+            }
+            match opened.index.flush(batch) {
+                Ok(()) => {
+                    opened.last_flush.store(now_millis(), Ordering::SeqCst);
+                    opened.deleted_since_compaction += results
+                        .iter()
+                        .filter(|result| **result == JsonValue::True)
+                        .count();
+                    if let Some(threshold) = opened.compact_deleted_threshold {
+                        // one compaction at a time, a running one may not
+                        // include all deletes anymore
+                        if opened.deleted_since_compaction >= threshold
+                            && !opened.compacting.swap(true, Ordering::SeqCst)
+                        {
+                            opened.deleted_since_compaction = 0;
+                            compact_in_background(index.index.clone());
+                        }
+                    }
+                    Message::ResponseOk(JsonValue::Array(results))
+                }
+                Err(reason) => Message::ResponseError(reason.to_string()),
+            }
+        }
+        Message::Query(query, params) => {
+            let index = &index.read().index;
+            let msg = match index.query(&query, params) {
+                Ok(results) => {
+                    let mut vec: Vec<JsonValue> = results.collect();
+                    vec.reverse(); // reverse so the client iterator can pop vals off end.
+                    Message::ResponseOk(JsonValue::Array(vec))
+                }
+                Err(reason) => Message::ResponseError(reason.to_string()),
+            };
+            msg
+        }
+        Message::RegisterQuery(name, query, params) => {
+            let filter = match percolation_filter(&query) {
+                Ok(filter) => filter,
+                Err(reason) => return Message::ResponseError(reason),
+            };
+            let mut opened = write_lock(index);
+            // make sure the filter parses before accepting it, by running it
+            // against the empty scratch index
+            let check = Percolator::get(&mut opened.percolator)
+                .and_then(|percolator| percolator.matches(&filter, params.clone()));
+            if let Err(reason) = check {
+                return Message::ResponseError(reason);
+            }
+            opened
+                .registered_queries
+                .insert(name, RegisteredQuery { filter, params });
+            Message::ResponseOk(JsonValue::True)
+        }
+        Message::UnregisterQuery(name) => {
+            let mut opened = write_lock(index);
+            match opened.registered_queries.remove(&name) {
+                Some(_) => Message::ResponseOk(JsonValue::True),
+                None => Message::ResponseOk(JsonValue::False),
+            }
+        }
+        Message::Info => match index_info(index) {
+            Ok(info) => Message::ResponseOk(info),
+            Err(reason) => Message::ResponseError(reason),
+        },
+        Message::Schema(sample_size) => match collect_schema(&index.read().index, sample_size) {
+            Ok((doc_count, schema)) => Message::ResponseOk(schema_json(doc_count, schema)),
+            Err(reason) => Message::ResponseError(reason),
+        },
+        Message::Backup(backup_dir, keep) => {
+            match backup::create_backup(&index.read().index, &backup_dir, keep) {
+                Ok(info) => Message::ResponseOk(info.to_json()),
+                Err(reason) => Message::ResponseError(reason),
+            }
+        }
+        Message::Compact => {
+            write_lock(index).deleted_since_compaction = 0;
+            match compact(&index.read().index) {
+                Ok(sizes) => Message::ResponseOk(sizes),
+                Err(reason) => Message::ResponseError(reason),
+            }
+        }
+        // the instance is closed by dropping it
+        Message::Close => Message::ResponseError("Close an instance by dropping it".to_string()),
+        Message::ResponseOk(_) | Message::ResponseError(_) => {
+            Message::ResponseError("Responses can't be sent to an instance".to_string())
+        }
+        Message::OpenIndex(_, _)
+        | Message::DropIndex(_, _)
+        | Message::Restore(_, _, _)
+        | Message::ListBackups(_)
+        | Message::MemoryUsage
+        | Message::ListIndexes(_)
+        | Message::CopyIndex(_, _)
+        | Message::RenameIndex(_, _) => Message::ResponseError(
+            "Not a request for an instance, send it with process_request".to_string(),
+        ),
+    }
+}
+
+// Compacts the whole index and returns the size of the index on disk before
+// and after.
+fn compact(index: &Index) -> Result<JsonValue, String> {
+    let path = Path::new(index.get_name());
+    let before = dir_size(path).map_err(|err| err.to_string())?;
+    compact_all(index);
+    let after = dir_size(path).map_err(|err| err.to_string())?;
+    Ok(JsonValue::Object(vec![
+        ("bytesBefore".to_string(), JsonValue::Number(before as f64)),
+        ("bytesAfter".to_string(), JsonValue::Number(after as f64)),
+    ]))
+}
+
+// Compacts all column families of the index: the default one with the
+// documents and the one with the R-tree of the geometries.
+fn compact_all(index: &Index) {
+    index.rocks.compact_range(None, None);
+    if let Some(rtree) = index.rocks.cf_handle("rtree") {
+        index.rocks.compact_range_cf(rtree, None, None);
+    }
+}
+
+// Compacts the whole index on its own thread, so the serving thread can go on.
+// The compaction isn't an instance of the index, but keeps it open. If the
+// last instance was closed meanwhile, the index is closed once it's done.
+fn compact_in_background(index: Arc<MvccRwLock<OpenedIndex>>) {
+    thread::spawn(move || {
+        compact_all(&index.read().index);
+        let mut guard = match OPEN_INSTANCES.lock() {
+            Ok(guard) => guard,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        let map = guard.deref_mut();
+        let opened = index.read();
+        opened.compacting.store(false, Ordering::SeqCst);
+        if opened.open_count.load(Ordering::SeqCst) == 0
+            && map
+                .get(&opened.name)
+                .is_some_and(|entry| Arc::ptr_eq(entry, &index))
+        {
+            map.remove(&opened.name);
+        }
+    });
+}
+
+// Read-only instances of an index that isn't open in this process are served
+// from a view of it, a copy of its directory with the table files hard linked.
+// Opening the view doesn't need the LOCK of the index, so it works while
+// another process has the index open. The view is the state of the index when
+// it was opened, later changes aren't seen.
+const READ_ONLY_PREFIX: &str = ":read-only:";
+
+// The prefixes of the names of in-memory indexes in `OPEN_INSTANCES`. Those
+// opened as ":memory:" are numbered, the others keep their name.
+const ANONYMOUS_MEMORY_PREFIX: &str = ":memory:anonymous:";
+const NAMED_MEMORY_PREFIX: &str = ":memory:named:";
+
+// Attempts at linking a view while the index is changed by another process.
+const VIEW_ATTEMPTS: usize = 5;
+
+// The names of the directories of views and of in-memory indexes start with
+// this, followed by the process id
+const VIEW_PREFIX: &str = "noise-read-only-";
+const MEMORY_PREFIX: &str = "noise-memory-";
+
+// Creates a view of the index `name` and returns its directory. Table files
+// that can't be hard linked are copied if `copy_tables` is set.
+fn link_view(name: &str) -> Result<PathBuf, String> {
+    let src = Path::new(name);
+    if !src.join("CURRENT").exists() {
+        return Err(format!("Index '{}' doesn't exist", name));
+    }
+    remove_stale_views();
+    let mut last_err = String::new();
+    for _ in 0..VIEW_ATTEMPTS {
+        let path = env::temp_dir().join(format!(
+            "{}{}-{}",
+            VIEW_PREFIX,
+            process::id(),
+            MEMORY_INDEXES.fetch_add(1, Ordering::SeqCst)
+        ));
+        match link_consistent_view(src, &path) {
+            Ok(true) => return Ok(path),
+            Ok(false) => last_err = "the index kept changing".to_string(),
+            // a compaction may remove files while they're linked
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => last_err = err.to_string(),
+            Err(err) => {
+                let _ = fs::remove_dir_all(&path);
+                return Err(format!(
+                    "Can't open a read-only view of '{}': {}",
+                    name, err
+                ));
+            }
+        }
+        let _ = fs::remove_dir_all(&path);
+    }
+    Err(format!(
+        "Can't open a read-only view of '{}': {}",
+        name, last_err
+    ))
+}
+
+// Links the files of the index in `src` into `dest`. Returns false if the
+// index was flushed or compacted meanwhile, as then the copied files may not
+// match. Flushes and compactions always append to the MANIFEST.
+fn link_consistent_view(src: &Path, dest: &Path) -> io::Result<bool> {
+    let manifest = |src: &Path| -> io::Result<(String, u64)> {
+        let current = fs::read_to_string(src.join("CURRENT"))?;
+        let current = current.trim().to_string();
+        let len = fs::metadata(src.join(&current))?.len();
+        Ok((current, len))
+    };
+    let before = manifest(src)?;
+    copy_index_files(src, dest, Tables::Link)?;
+    Ok(manifest(src)? == before)
+}
+
+// A new directory for an in-memory index. Linux has a file system backed by
+// memory at /dev/shm. RocksDB can only keep a database in memory with options
+// noise doesn't allow to set, so the index is stored there.
+fn memory_index_dir() -> PathBuf {
+    let shm = Path::new("/dev/shm");
+    let dir = if shm.is_dir() {
+        shm.to_path_buf()
+    } else {
+        env::temp_dir()
+    };
+    dir.join(format!(
+        "{}{}-{}",
+        MEMORY_PREFIX,
+        process::id(),
+        MEMORY_INDEXES.fetch_add(1, Ordering::SeqCst)
+    ))
+}
+
+// Temporary indexes are named `<prefix>-tmp-<pid>-<suffix>`. Removes those with
+// the same prefix as `path` that were left behind by processes which ended
+// without closing them, e.g. because they crashed.
+fn remove_stale_temp_indexes(path: &Path) {
+    let file_name = match path.file_name() {
+        Some(file_name) => file_name.to_string_lossy().into_owned(),
+        None => return,
+    };
+    let prefix = match file_name.rfind("-tmp-") {
+        Some(pos) => &file_name[..pos + "-tmp-".len()],
+        None => return,
+    };
+    let dir = match path.parent() {
+        Some(dir) if dir != Path::new("") => dir,
+        _ => Path::new("."),
+    };
+    remove_dead_process_dirs(dir, prefix, true);
+}
+
+// Removes the views of read-only instances and the in-memory indexes that
+// processes which ended without closing them left behind. Only done once per
+// process, as the temporary directory may be big.
+fn remove_stale_views() {
+    static SWEPT: Once = Once::new();
+    SWEPT.call_once(|| {
+        remove_dead_process_dirs(&env::temp_dir(), VIEW_PREFIX, false);
+        remove_dead_process_dirs(Path::new("/dev/shm"), MEMORY_PREFIX, false);
+    });
+}
+
+// Removes the directories in `dir` named `<prefix><pid>-<suffix>` whose process
+// isn't running anymore. If `only_indexes` is set, only those that are noise
+// indexes. It's only possible to tell whether a process is still running where
+// there is a /proc file system.
+fn remove_dead_process_dirs(dir: &Path, prefix: &str, only_indexes: bool) {
+    let proc_dir = Path::new("/proc");
+    if !proc_dir.join("self").exists() {
+        return;
+    }
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    for entry in entries.filter_map(|entry| entry.ok()) {
+        let entry_name = entry.file_name().to_string_lossy().into_owned();
+        let pid = match entry_name.strip_prefix(prefix) {
+            Some(rest) => rest.split('-').next().unwrap_or(""),
+            None => continue,
+        };
+        let alive = match pid.parse::<u32>() {
+            Ok(pid) => pid == process::id() || proc_dir.join(pid.to_string()).exists(),
+            Err(_) => continue,
+        };
+        if !alive && (!only_indexes || is_noise_index(&entry.path()).unwrap_or(false)) {
+            let _ = fs::remove_dir_all(entry.path());
+        }
+    }
+}
+
+// Returns the filter of a query registered for percolation, the part after
+// `find` up to the first `order`, `return` or `limit` clause. Those clauses
+// don't matter for whether a document matches.
+fn percolation_filter(query: &str) -> Result<String, String> {
+    let filter = match query.trim_start().strip_prefix("find") {
+        Some(filter) => filter,
+        None => return Err("Missing 'find' keyword".to_string()),
+    };
+    let is_word = |byte: u8| byte.is_ascii_alphanumeric() || byte == b'_';
+    let bytes = filter.as_bytes();
+    let mut depth = 0;
+    let mut in_string = false;
+    let mut escaped = false;
+    for (pos, &byte) in bytes.iter().enumerate() {
+        if in_string {
+            if escaped {
+                escaped = false;
+            } else if byte == b'\\' {
+                escaped = true;
+            } else if byte == b'"' {
+                in_string = false;
+            }
+            continue;
+        }
+        match byte {
+            b'"' => in_string = true,
+            b'{' | b'[' | b'(' => depth += 1,
+            b'}' | b']' | b')' => depth -= 1,
+            _ if depth == 0 && (pos == 0 || !is_word(bytes[pos - 1])) => {
+                let rest = &bytes[pos..];
+                let is_clause = ["order", "return", "limit"].iter().any(|keyword| {
+                    rest.starts_with(keyword.as_bytes())
+                        && !rest.get(keyword.len()).is_some_and(|&next| is_word(next))
+                });
+                if is_clause {
+                    return Ok(filter[..pos].trim().to_string());
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(filter.trim().to_string())
+}
+
+// Replaces each successfully added document id in `results` with an object
+// holding the id and the names of the registered queries the document matches.
+// Each document is matched on its own, in the scratch index, so it doesn't
+// matter what else is in the index or the batch. The documents are added
+// already, so a document that can't be matched gets an error next to its id.
+fn percolate(opened: &mut OpenedIndex, docs: &[String], results: Vec<JsonValue>) -> Vec<JsonValue> {
+    let registered_queries = &opened.registered_queries;
+    let mut names: Vec<&String> = registered_queries.keys().collect();
+    names.sort();
+    let mut percolated = Vec::with_capacity(results.len());
+    for (doc, result) in docs.iter().zip(results) {
+        let id = match result {
+            JsonValue::String(id) => id,
+            error => {
+                percolated.push(error);
+                continue;
+            }
+        };
+        let matched = if names.is_empty() {
+            Ok(Vec::new())
+        } else {
+            Percolator::get(&mut opened.percolator)
+                .and_then(|percolator| percolator.match_doc(doc, &id, &names, registered_queries))
+        };
+        let field = match matched {
+            Ok(matches) => ("matches".to_string(), JsonValue::Array(matches)),
+            Err(reason) => {
+                // the scratch index may still hold the document, start over
+                opened.percolator = None;
+                ("error".to_string(), JsonValue::String(reason))
+            }
+        };
+        percolated.push(JsonValue::Object(vec![
+            ("_id".to_string(), JsonValue::String(id)),
+            field,
+        ]));
+    }
+    percolated
+}
+
+// Collects the statistics returned by `index.info()`.
+fn index_info(index: &OpenedIndexCleanupGuard) -> Result<JsonValue, String> {
+    let opened = index.read();
+    let name = opened
+        .name
+        .strip_prefix(READ_ONLY_PREFIX)
+        .unwrap_or(&opened.name);
+    let path = Path::new(opened.index.get_name());
+    let open_count = opened.open_count.load(Ordering::SeqCst);
+
+    // the documents and their paths are counted in the same scan
+    let (doc_count, schema) = collect_schema(&opened.index, None)?;
+    let disk_size = dir_size(path).map_err(|err| err.to_string())?;
+    let last_flush = match opened.last_flush.load(Ordering::SeqCst) {
+        0 => JsonValue::Null,
+        millis => JsonValue::Number(millis as f64),
+    };
+    let rocksdb = rocksdb_stats(path).map_err(|err| err.to_string())?;
+
+    Ok(JsonValue::Object(vec![
+        ("name".to_string(), JsonValue::String(name.to_string())),
+        ("docCount".to_string(), JsonValue::Number(doc_count as f64)),
+        ("diskSize".to_string(), JsonValue::Number(disk_size as f64)),
+        (
+            "fieldPaths".to_string(),
+            JsonValue::Number(schema.len() as f64),
+        ),
+        ("lastFlush".to_string(), last_flush),
+        (
+            "openCount".to_string(),
+            JsonValue::Number(open_count as f64),
+        ),
+        ("rocksdb".to_string(), rocksdb),
+    ]))
+}
+
+fn doc_count(index: &Index) -> Result<f64, String> {
+    match index.query("find {} return count()", None) {
+        Ok(mut results) => match results.next() {
+            Some(JsonValue::Number(count)) => Ok(count),
+            _ => Ok(0.0),
+        },
+        Err(reason) => Err(reason.to_string()),
+    }
+}
+
+// Finds the noise indexes in `dir` and the directories below it. For each one
+// the name to open it with, its size on disk, the number of documents and
+// whether it is open in this process are returned, sorted by name.
+fn list_indexes(dir: &Path) -> Result<Vec<JsonValue>, String> {
+    let mut paths = Vec::new();
+    find_indexes(dir, &mut paths).map_err(|err| err.to_string())?;
+    paths.sort();
+
+    // the indexes open in this process by their canonical path, as the same
+    // index can be opened by different names. The lock isn't kept while
+    // counting the documents, so opening and closing indexes can go on.
+    let open: Vec<(PathBuf, Arc<MvccRwLock<OpenedIndex>>)> = {
+        let guard = OPEN_INSTANCES.lock().unwrap();
+        guard
+            .iter()
+            .filter(|&(name, _)| {
+                !name.starts_with(ANONYMOUS_MEMORY_PREFIX) && !name.starts_with(NAMED_MEMORY_PREFIX)
+            })
+            .filter_map(|(name, opened)| {
+                let name = name.trim_start_matches(READ_ONLY_PREFIX);
+                let path = fs::canonicalize(name).ok()?;
+                Some((path, opened.clone()))
+            })
+            .collect()
+    };
+    let mut indexes = Vec::with_capacity(paths.len());
+    for path in paths {
+        let name = path.to_string_lossy().into_owned();
+        let canonical = fs::canonicalize(&path).map_err(|err| err.to_string())?;
+        let opened = open
+            .iter()
+            .find(|&(open_path, _)| *open_path == canonical)
+            .map(|(_, opened)| opened);
+        let doc_count = match opened {
+            Some(opened) => doc_count(&opened.read().index).ok(),
+            None => closed_doc_count(&name),
+        };
+        let size = dir_size(&path).map_err(|err| err.to_string())?;
+        indexes.push(JsonValue::Object(vec![
+            ("name".to_string(), JsonValue::String(name)),
+            ("size".to_string(), JsonValue::Number(size as f64)),
+            (
+                "docCount".to_string(),
+                doc_count.map_or(JsonValue::Null, JsonValue::Number),
+            ),
+            (
+                "open".to_string(),
+                if opened.is_some() {
+                    JsonValue::True
+                } else {
+                    JsonValue::False
+                },
+            ),
+        ]));
+    }
+    Ok(indexes)
+}
+
+// Counts the documents of the closed index `name` in a view of it, so the
+// index isn't locked or written to. None if there can't be a view, e.g.
+// because the temporary directory is on another file system, or another
+// process keeps changing the index.
+fn closed_doc_count(name: &str) -> Option<f64> {
+    let path = link_view(name).ok()?;
+    let _remove_on_close = RemoveOnClose::new(path.clone());
+    let index = Index::open(&path.to_string_lossy(), None).ok()?;
+    doc_count(&index).ok()
+}
+
+fn find_indexes(dir: &Path, paths: &mut Vec<PathBuf>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if !entry.file_type()?.is_dir() {
+            continue;
+        }
+        let path = entry.path();
+        if is_noise_index(&path)? {
+            paths.push(path);
+        } else {
+            find_indexes(&path, paths)?;
+        }
+    }
+    Ok(())
+}
+
+// Makes sure the index directory `name` is within the base directory. The
+// directory doesn't need to exist yet.
+fn check_base_dir(name: &str) -> Result<(), String> {
+    let base_dir = BASE_DIR.lock().unwrap().clone();
+    if let Some(base_dir) = base_dir {
+        let base_dir = fs::canonicalize(&base_dir).map_err(|err| {
+            format!(
+                "Base directory '{}' is invalid: {}",
+                base_dir.display(),
+                err
+            )
+        })?;
+        let path = canonical_path(Path::new(name)).map_err(|err| err.to_string())?;
+        if path == base_dir || !path.starts_with(&base_dir) {
+            return Err(format!(
+                "'{}' is outside of the base directory '{}'",
+                name,
+                base_dir.display()
+            ));
+        }
+    }
+    Ok(())
+}
+
+// Resolves `path` like `fs::canonicalize`, but also if its last components
+// don't exist yet. Those mustn't be `..`, as it can't be resolved.
+fn canonical_path(path: &Path) -> io::Result<PathBuf> {
+    let mut missing = Vec::new();
+    let mut existing = path;
+    loop {
+        let result = if existing.as_os_str().is_empty() {
+            fs::canonicalize(".")
+        } else {
+            fs::canonicalize(existing)
+        };
+        match result {
+            Ok(mut canonical) => {
+                for component in missing.iter().rev() {
+                    canonical.push(component);
+                }
+                return Ok(canonical);
+            }
+            Err(err) => match (existing.parent(), existing.file_name()) {
+                (Some(parent), Some(file_name)) => {
+                    missing.push(file_name);
+                    existing = parent;
+                }
+                _ => return Err(err),
+            },
+        }
+    }
+}
+
+// Makes sure dropping `name` only deletes a noise index within the base
+// directory, and returns the files that would be deleted along with their
+// sizes.
+fn files_to_drop(name: &str) -> Result<Vec<(String, u64)>, String> {
+    let path = Path::new(name);
+    if !path.exists() {
+        // nothing to delete
+        return Ok(Vec::new());
+    }
+    check_base_dir(name)?;
+    // anything but an empty directory must be a noise index
+    let mut files = Vec::new();
+    let mut empty = true;
+    for entry in fs::read_dir(path).map_err(|err| err.to_string())? {
+        let entry = entry.map_err(|err| err.to_string())?;
+        empty = false;
+        let metadata = entry.metadata().map_err(|err| err.to_string())?;
+        if !metadata.is_dir() {
+            let file_name = entry.file_name().to_string_lossy().into_owned();
+            files.push((file_name, metadata.len()));
+        }
+    }
+    if !empty && !is_noise_index(path).map_err(|err| err.to_string())? {
+        return Err(format!("'{}' isn't a noise index", name));
+    }
+    files.sort();
+    Ok(files)
+}
+
+// Copies or renames the index `src` to `dest` if neither one is open.
+fn copy_closed_index(src: &str, dest: &str, rename: bool) -> Message {
+    // keep the lock so neither index can be opened meanwhile
+    let guard = OPEN_INSTANCES.lock().unwrap();
+    if guard.contains_key(src) || guard.contains_key(dest) {
+        return Message::ResponseError("Index instances still open".to_string());
+    }
+    let result = if rename {
+        rename_index(src, dest)
+    } else {
+        copy_index(src, dest)
+    };
+    match result {
+        Ok(()) => Message::ResponseOk(JsonValue::True),
+        Err(msg) => Message::ResponseError(msg),
+    }
+}
+
+// Makes sure `src` is an index and `dest` doesn't exist yet, both within the
+// base directory.
+fn check_copy(src: &str, dest: &str) -> Result<(), String> {
+    check_base_dir(src)?;
+    check_base_dir(dest)?;
+    if !is_noise_index(Path::new(src)).map_err(|err| err.to_string())? {
+        return Err(format!("'{}' isn't a noise index", src));
+    }
+    if Path::new(dest).exists() {
+        return Err(format!("'{}' already exists", dest));
+    }
+    Ok(())
+}
+
+// Copies the closed index `src` to `dest`. The table files are never changed
+// once written, so they are hard linked instead of copied if possible. The
+// identity of the index isn't copied, so the copy gets a new one.
+fn copy_index(src: &str, dest: &str) -> Result<(), String> {
+    check_copy(src, dest)?;
+    let result = copy_index_files(Path::new(src), Path::new(dest), Tables::LinkOrCopy);
+    if result.is_err() {
+        let _ = fs::remove_dir_all(dest);
+    }
+    result.map_err(|err| err.to_string())
+}
+
+// How `copy_index_files` gets the table files into the copy.
+#[derive(Clone, Copy, PartialEq)]
+enum Tables {
+    // hard linked, or copied where they can't be
+    LinkOrCopy,
+    // hard linked, or symbolically linked if the index is on read-only storage,
+    // as then they can't be deleted while they're linked
+    Link,
+}
+
+fn copy_index_files(src: &Path, dest: &Path, tables: Tables) -> io::Result<()> {
+    fs::create_dir_all(dest)?;
+    let read_only_src = is_read_only_storage(src);
+    for entry in fs::read_dir(src)? {
+        let entry = entry?;
+        if !entry.file_type()?.is_file() {
+            continue;
+        }
+        let file_name = entry.file_name();
+        let name = file_name.to_string_lossy();
+        if name == "LOCK" || name == "IDENTITY" || name.starts_with("LOG") {
+            continue;
+        }
+        let target = dest.join(&file_name);
+        if !name.ends_with(".sst") {
+            fs::copy(entry.path(), &target)?;
+        } else if let Err(err) = fs::hard_link(entry.path(), &target) {
+            // linking fails across file systems
+            match tables {
+                Tables::LinkOrCopy => {
+                    fs::copy(entry.path(), &target)?;
+                }
+                Tables::Link if read_only_src => {
+                    symlink(fs::canonicalize(entry.path())?, &target)?;
+                }
+                Tables::Link => {
+                    return Err(io::Error::new(
+                        err.kind(),
+                        format!(
+                            "its files can't be linked into '{}' ({}). The temporary \
+                             directory must be on the same file system as the index, \
+                             set TMPDIR to change it",
+                            dest.parent().unwrap_or(dest).display(),
+                            err
+                        ),
+                    ));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+// Whether `path` is on a file system mounted read-only.
+fn is_read_only_storage(path: &Path) -> bool {
+    let path = match CString::new(path.as_os_str().as_bytes()) {
+        Ok(path) => path,
+        Err(_) => return false,
+    };
+    let mut stat: libc::statvfs = unsafe { mem::zeroed() };
+    unsafe { libc::statvfs(path.as_ptr(), &mut stat) == 0 && stat.f_flag & libc::ST_RDONLY != 0 }
+}
+
+// Moves the closed index `src` to `dest`.
+fn rename_index(src: &str, dest: &str) -> Result<(), String> {
+    check_copy(src, dest)?;
+    if let Some(parent) = Path::new(dest).parent() {
+        fs::create_dir_all(parent).map_err(|err| err.to_string())?;
+    }
+    fs::rename(src, dest).map_err(|err| err.to_string())
+}
+
+// Builds the response of a drop with the `dryRun` option.
+fn dry_run_json(name: &str, files: Vec<(String, u64)>) -> JsonValue {
+    let size = files.iter().map(|&(_, size)| size).sum::<u64>();
+    let files = files
+        .into_iter()
+        .map(|(file_name, _)| JsonValue::String(file_name))
+        .collect();
+    JsonValue::Object(vec![
+        ("name".to_string(), JsonValue::String(name.to_string())),
+        ("files".to_string(), JsonValue::Array(files)),
+        ("size".to_string(), JsonValue::Number(size as f64)),
+    ])
+}
+
+// Whether `path` is the directory of a noise index. RocksDB stores the options
+// a database was opened with in `OPTIONS-*` files, which name the comparator
+// noise uses.
+fn is_noise_index(path: &Path) -> io::Result<bool> {
+    if !path.join("CURRENT").is_file() {
+        return Ok(false);
+    }
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        if entry.file_name().to_string_lossy().starts_with("OPTIONS-") {
+            let mut options = String::new();
+            fs::File::open(entry.path())?.read_to_string(&mut options)?;
+            if options.contains("comparator=noise_cmp") {
+                return Ok(true);
+            }
+        }
+    }
+    Ok(false)
+}
+
+// What was observed at one key path of the documents.
+#[derive(Default)]
+struct FieldStats {
+    // number of documents containing the path
+    docs: usize,
+    // number of values of each JSON type found at the path
+    types: BTreeMap<&'static str, usize>,
+}
+
+// Walks the documents of the index and records every key path with the types
+// of values found there. Array elements are represented by `[]`, e.g.
+// `foo[].bar`. If `sample_size` is given only that many documents are read.
+// Returns the number of documents read along with the paths.
+fn collect_schema(
+    index: &Index,
+    sample_size: Option<usize>,
+) -> Result<(usize, BTreeMap<String, FieldStats>), String> {
+    let query = match sample_size {
+        Some(size) => format!("find {{}} return . limit {}", size),
+        None => "find {} return .".to_string(),
+    };
+    let docs = index
+        .query(&query, None)
+        .map_err(|reason| reason.to_string())?;
+    let mut schema = BTreeMap::new();
+    let mut doc_count = 0;
+    for doc in docs {
+        doc_count += 1;
+        let mut doc_paths = BTreeSet::new();
+        collect_paths(&doc, "", &mut schema, &mut doc_paths);
+        for path in doc_paths {
+            if let Some(stats) = schema.get_mut(&path) {
+                stats.docs += 1;
+            }
+        }
+    }
+    Ok((doc_count, schema))
+}
+
+fn collect_paths(
+    json: &JsonValue,
+    path: &str,
+    schema: &mut BTreeMap<String, FieldStats>,
+    doc_paths: &mut BTreeSet<String>,
+) {
+    match *json {
+        JsonValue::Object(ref fields) => {
+            for (key, value) in fields {
+                let child = if path.is_empty() {
+                    key.clone()
+                } else {
+                    format!("{}.{}", path, key)
+                };
+                record_type(value, &child, schema, doc_paths);
+                collect_paths(value, &child, schema, doc_paths);
+            }
+        }
+        JsonValue::Array(ref elements) => {
+            let child = format!("{}[]", path);
+            for element in elements {
+                record_type(element, &child, schema, doc_paths);
+                collect_paths(element, &child, schema, doc_paths);
+            }
+        }
+        _ => (),
+    }
+}
+
+fn record_type(
+    json: &JsonValue,
+    path: &str,
+    schema: &mut BTreeMap<String, FieldStats>,
+    doc_paths: &mut BTreeSet<String>,
+) {
+    let json_type = match *json {
+        JsonValue::Number(_) => "number",
+        JsonValue::String(_) => "string",
+        JsonValue::True | JsonValue::False => "bool",
+        JsonValue::Null => "null",
+        JsonValue::Array(_) => "array",
+        JsonValue::Object(_) => "object",
+    };
+    let stats = schema.entry(path.to_string()).or_default();
+    *stats.types.entry(json_type).or_insert(0) += 1;
+    doc_paths.insert(path.to_string());
+}
+
+// Builds the response of `index.schema()`.
+fn schema_json(doc_count: usize, schema: BTreeMap<String, FieldStats>) -> JsonValue {
+    let paths = schema
+        .into_iter()
+        .map(|(path, stats)| {
+            let types = stats
+                .types
+                .into_iter()
+                .map(|(json_type, count)| (json_type.to_string(), JsonValue::Number(count as f64)))
+                .collect();
+            let stats = vec![
+                ("docs".to_string(), JsonValue::Number(stats.docs as f64)),
+                ("types".to_string(), JsonValue::Object(types)),
+            ];
+            (path, JsonValue::Object(stats))
+        })
+        .collect();
+    JsonValue::Object(vec![
+        ("docCount".to_string(), JsonValue::Number(doc_count as f64)),
+        ("paths".to_string(), JsonValue::Object(paths)),
+    ])
+}
+
+fn dir_size(path: &Path) -> io::Result<u64> {
+    let mut size = 0;
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        if metadata.is_dir() {
+            size += dir_size(&entry.path())?;
+        } else {
+            size += metadata.len();
+        }
+    }
+    Ok(size)
+}
+
+// The RocksDB wrapper noise is built on doesn't expose the DB properties, so
+// the storage files are summarized from the index directory.
+fn rocksdb_stats(path: &Path) -> io::Result<JsonValue> {
+    let files = StorageFiles::read(path)?;
+    Ok(JsonValue::Object(vec![
+        (
+            "sstFiles".to_string(),
+            JsonValue::Number(files.sst_files as f64),
+        ),
+        (
+            "sstBytes".to_string(),
+            JsonValue::Number(files.sst_bytes as f64),
+        ),
+        (
+            "walFiles".to_string(),
+            JsonValue::Number(files.wal_files as f64),
+        ),
+        (
+            "walBytes".to_string(),
+            JsonValue::Number(files.wal_bytes as f64),
+        ),
+    ]))
+}
+
+// The table files and write-ahead logs of an index directory.
+struct StorageFiles {
+    sst_files: u64,
+    sst_bytes: u64,
+    wal_files: u64,
+    wal_bytes: u64,
+}
+
+impl StorageFiles {
+    fn read(path: &Path) -> io::Result<StorageFiles> {
+        let mut files = StorageFiles {
+            sst_files: 0,
+            sst_bytes: 0,
+            wal_files: 0,
+            wal_bytes: 0,
+        };
+        for entry in fs::read_dir(path)? {
+            let entry = entry?;
+            let file_name = entry.file_name();
+            let file_name = file_name.to_string_lossy();
+            if file_name.ends_with(".sst") {
+                files.sst_files += 1;
+                files.sst_bytes += entry.metadata()?.len();
+            } else if file_name.ends_with(".log") {
+                files.wal_files += 1;
+                files.wal_bytes += entry.metadata()?.len();
+            }
+        }
+        Ok(files)
+    }
+}
+
+// Reports the memory used by the process and the open indexes. The RocksDB
+// wrapper noise is built on doesn't expose the memory usage properties, so
+// only what can be measured is reported: the resident memory of the whole
+// process, and which indexes are open. A memory budget or a block cache shared
+// between indexes needs `Index::open` of noise_search to take RocksDB options.
+fn memory_usage() -> JsonValue {
+    let opened: Vec<(String, Arc<MvccRwLock<OpenedIndex>>)> = {
+        let guard = OPEN_INSTANCES.lock().unwrap();
+        guard
+            .iter()
+            .map(|(name, opened)| (name.clone(), opened.clone()))
+            .collect()
+    };
+    let mut indexes = Vec::with_capacity(opened.len());
+    for (name, opened) in opened {
+        let open_count = opened.read().open_count.load(Ordering::SeqCst);
+        let read_only = name.starts_with(READ_ONLY_PREFIX);
+        let name = name.trim_start_matches(READ_ONLY_PREFIX).to_string();
+        indexes.push(JsonValue::Object(vec![
+            ("name".to_string(), JsonValue::String(name)),
+            (
+                "openCount".to_string(),
+                JsonValue::Number(open_count as f64),
+            ),
+            (
+                "readOnlyView".to_string(),
+                if read_only {
+                    JsonValue::True
+                } else {
+                    JsonValue::False
+                },
+            ),
+        ]));
+    }
+    let rss = match resident_memory() {
+        Some(rss) => JsonValue::Number(rss as f64),
+        None => JsonValue::Null,
+    };
+    JsonValue::Object(vec![
+        ("rss".to_string(), rss),
+        ("indexes".to_string(), JsonValue::Array(indexes)),
+    ])
+}
+
+// The resident memory of the process in bytes. Only known where there is a
+// /proc file system.
+fn resident_memory() -> Option<u64> {
+    let statm = fs::read_to_string("/proc/self/statm").ok()?;
+    let pages: u64 = statm.split_whitespace().nth(1)?.parse().ok()?;
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+    if page_size <= 0 {
+        return None;
+    }
+    Some(pages * page_size as u64)
+}
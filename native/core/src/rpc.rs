@@ -0,0 +1,336 @@
+// JSON-RPC 2.0 for using indexes from other programs without Node.js. Each
+// line of the input is a request or a batch of them, each response is written
+// as a line of the output. The requests are handled like those of the
+// Javascript API:
+//
+// - `open` {name, options}: opens an instance and returns its handle. The
+//   options are `createIfMissing`, `errorIfExists`, `readOnly`, `inMemory` and
+//   `compactDeletedThreshold`
+// - `add` {handle, docs}: adds a document or an array of them
+// - `delete` {handle, ids}: deletes a document or an array of them by id
+// - `query` {handle, query, params}: returns all results of the query
+// - `close` {handle}: closes the instance
+// - `drop` {name, options}: deletes the index. The options are `dryRun` and
+//   `force`
+
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, BufRead, Write};
+
+use noise_search::json_value::{JsonValue, PrettyPrint};
+use rustc_serialize::json::Json;
+
+use {
+    open_instance, process_message, process_request, AddOptions, DropOptions, IndexOpenOptions,
+    Message, OpenedIndexCleanupGuard,
+};
+
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+// errors of the index, like a query that can't be parsed
+const INDEX_ERROR: i64 = -32000;
+
+struct Server {
+    // the open instances keyed by their handle
+    instances: HashMap<u64, OpenedIndexCleanupGuard>,
+    next_handle: u64,
+}
+
+// Serves requests from `input` until it ends. The instances left open are
+// closed then.
+pub fn serve<R: BufRead, W: Write>(input: R, mut output: W) -> io::Result<()> {
+    let mut server = Server {
+        instances: HashMap::new(),
+        next_handle: 1,
+    };
+    for line in input.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let resp = match Json::from_str(&line) {
+            Ok(Json::Array(ref batch)) if batch.is_empty() => Some(error_response(
+                JsonValue::Null,
+                INVALID_REQUEST,
+                "empty batch".to_string(),
+            )),
+            Ok(Json::Array(batch)) => {
+                let resps: Vec<JsonValue> = batch
+                    .iter()
+                    .filter_map(|request| server.handle(request))
+                    .collect();
+                // a batch of notifications gets no response at all
+                if resps.is_empty() {
+                    None
+                } else {
+                    Some(JsonValue::Array(resps))
+                }
+            }
+            Ok(request) => server.handle(&request),
+            Err(err) => Some(error_response(
+                JsonValue::Null,
+                PARSE_ERROR,
+                err.to_string(),
+            )),
+        };
+        if let Some(resp) = resp {
+            let mut line = Vec::new();
+            resp.render(&mut line, &mut PrettyPrint::new("", "", ""))
+                .map_err(|err| io::Error::other(err.to_string()))?;
+            line.push(b'\n');
+            output.write_all(&line)?;
+            output.flush()?;
+        }
+    }
+    Ok(())
+}
+
+impl Server {
+    // Returns the response to the request, None for a notification.
+    fn handle(&mut self, request: &Json) -> Option<JsonValue> {
+        let request = match *request {
+            Json::Object(ref request) => request,
+            _ => {
+                return Some(error_response(
+                    JsonValue::Null,
+                    INVALID_REQUEST,
+                    "request must be an object".to_string(),
+                ))
+            }
+        };
+        let id = request.get("id").map(to_json_value);
+        let method = match (request.get("jsonrpc"), request.get("method")) {
+            (Some(Json::String(version)), Some(Json::String(method))) if version == "2.0" => method,
+            _ => {
+                return Some(error_response(
+                    id.unwrap_or(JsonValue::Null),
+                    INVALID_REQUEST,
+                    "not a JSON-RPC 2.0 request".to_string(),
+                ))
+            }
+        };
+        let empty = BTreeMap::new();
+        let params = match request.get("params") {
+            Some(Json::Object(params)) => params,
+            None => &empty,
+            Some(_) => {
+                return id.map(|id| {
+                    error_response(id, INVALID_PARAMS, "params must be an object".to_string())
+                })
+            }
+        };
+        let result = self.call(method, params);
+        // notifications don't get a response, not even for errors
+        let id = id?;
+        Some(match result {
+            Ok(result) => JsonValue::Object(vec![
+                ("jsonrpc".to_string(), JsonValue::String("2.0".to_string())),
+                ("id".to_string(), id),
+                ("result".to_string(), result),
+            ]),
+            Err((code, message)) => error_response(id, code, message),
+        })
+    }
+
+    fn call(
+        &mut self,
+        method: &str,
+        params: &BTreeMap<String, Json>,
+    ) -> Result<JsonValue, (i64, String)> {
+        match method {
+            "open" => {
+                let name = string_param(params, "name")?;
+                let options = IndexOpenOptions {
+                    create_if_missing: bool_option(params, "createIfMissing")?,
+                    error_if_exists: bool_option(params, "errorIfExists")?,
+                    read_only: bool_option(params, "readOnly")?,
+                    compact_deleted_threshold: number_option(params, "compactDeletedThreshold")?
+                        .map(|count| count as usize),
+                    in_memory: name == ":memory:" || bool_option(params, "inMemory")?,
+                    ..Default::default()
+                };
+                let index = open_instance(&name, &options).map_err(|msg| (INDEX_ERROR, msg))?;
+                let handle = self.next_handle;
+                self.next_handle += 1;
+                self.instances.insert(handle, index);
+                Ok(JsonValue::Number(handle as f64))
+            }
+            "add" => {
+                let docs = match params.get("docs") {
+                    Some(Json::Array(docs)) => docs.iter().map(|doc| doc.to_string()).collect(),
+                    Some(doc @ Json::Object(_)) => vec![doc.to_string()],
+                    _ => return Err(invalid_param("docs")),
+                };
+                self.request(params, Message::Add(docs, AddOptions { percolate: false }))
+            }
+            "delete" => {
+                let ids = match params.get("ids") {
+                    Some(Json::Array(ids)) => {
+                        let mut strings = Vec::with_capacity(ids.len());
+                        for id in ids {
+                            match *id {
+                                Json::String(ref id) => strings.push(id.clone()),
+                                _ => return Err(invalid_param("ids")),
+                            }
+                        }
+                        strings
+                    }
+                    Some(Json::String(id)) => vec![id.clone()],
+                    _ => return Err(invalid_param("ids")),
+                };
+                self.request(params, Message::Delete(ids))
+            }
+            "query" => {
+                let query = string_param(params, "query")?;
+                let query_params = match params.get("params") {
+                    Some(query_params @ Json::Object(_)) => Some(query_params.to_string()),
+                    None | Some(&Json::Null) => None,
+                    Some(_) => return Err(invalid_param("params")),
+                };
+                let results = self.request(params, Message::Query(query, query_params))?;
+                match results {
+                    // the results come reversed, for the Javascript iterator to pop them
+                    JsonValue::Array(mut results) => {
+                        results.reverse();
+                        Ok(JsonValue::Array(results))
+                    }
+                    results => Ok(results),
+                }
+            }
+            "close" => {
+                let handle = handle_param(params)?;
+                match self.instances.remove(&handle) {
+                    // the instance is closed once it's dropped
+                    Some(_instance) => Ok(JsonValue::True),
+                    None => Err(unknown_handle(handle)),
+                }
+            }
+            "drop" => {
+                let name = string_param(params, "name")?;
+                let options = DropOptions {
+                    dry_run: bool_option(params, "dryRun")?,
+                    force: bool_option(params, "force")?,
+                };
+                if options.force && !options.dry_run {
+                    // these instances would never notice the drop
+                    self.instances
+                        .retain(|_handle, instance| instance.read().name != name);
+                }
+                response(process_request(Message::DropIndex(name, options)))
+            }
+            _ => Err((METHOD_NOT_FOUND, format!("unknown method '{}'", method))),
+        }
+    }
+
+    // Sends the message to the instance with the handle in the params.
+    fn request(
+        &mut self,
+        params: &BTreeMap<String, Json>,
+        msg: Message,
+    ) -> Result<JsonValue, (i64, String)> {
+        let handle = handle_param(params)?;
+        match self.instances.get_mut(&handle) {
+            Some(instance) => response(process_message(instance, msg)),
+            None => Err(unknown_handle(handle)),
+        }
+    }
+}
+
+fn response(msg: Message) -> Result<JsonValue, (i64, String)> {
+    match msg {
+        Message::ResponseOk(json) => Ok(json),
+        Message::ResponseError(msg) => Err((INDEX_ERROR, msg)),
+        _ => panic!("Non-response message"),
+    }
+}
+
+fn error_response(id: JsonValue, code: i64, message: String) -> JsonValue {
+    JsonValue::Object(vec![
+        ("jsonrpc".to_string(), JsonValue::String("2.0".to_string())),
+        ("id".to_string(), id),
+        (
+            "error".to_string(),
+            JsonValue::Object(vec![
+                ("code".to_string(), JsonValue::Number(code as f64)),
+                ("message".to_string(), JsonValue::String(message)),
+            ]),
+        ),
+    ])
+}
+
+fn invalid_param(key: &str) -> (i64, String) {
+    (
+        INVALID_PARAMS,
+        format!("invalid or missing param '{}'", key),
+    )
+}
+
+fn unknown_handle(handle: u64) -> (i64, String) {
+    (
+        INVALID_PARAMS,
+        format!("no open instance with handle {}", handle),
+    )
+}
+
+fn string_param(params: &BTreeMap<String, Json>, key: &str) -> Result<String, (i64, String)> {
+    match params.get(key) {
+        Some(Json::String(value)) => Ok(value.clone()),
+        _ => Err(invalid_param(key)),
+    }
+}
+
+fn handle_param(params: &BTreeMap<String, Json>) -> Result<u64, (i64, String)> {
+    match params.get("handle").and_then(Json::as_u64) {
+        Some(handle) => Ok(handle),
+        None => Err(invalid_param("handle")),
+    }
+}
+
+// The options of `open` and `drop` are in the `options` param.
+fn option<'a>(
+    params: &'a BTreeMap<String, Json>,
+    key: &str,
+) -> Result<Option<&'a Json>, (i64, String)> {
+    match params.get("options") {
+        Some(Json::Object(options)) => Ok(options.get(key)),
+        None | Some(&Json::Null) => Ok(None),
+        Some(_) => Err(invalid_param("options")),
+    }
+}
+
+fn bool_option(params: &BTreeMap<String, Json>, key: &str) -> Result<bool, (i64, String)> {
+    match option(params, key)? {
+        Some(&Json::Boolean(value)) => Ok(value),
+        None => Ok(false),
+        Some(_) => Err(invalid_param(key)),
+    }
+}
+
+fn number_option(params: &BTreeMap<String, Json>, key: &str) -> Result<Option<u64>, (i64, String)> {
+    match option(params, key)? {
+        Some(value) => match value.as_u64() {
+            Some(value) => Ok(Some(value)),
+            None => Err(invalid_param(key)),
+        },
+        None => Ok(None),
+    }
+}
+
+fn to_json_value(json: &Json) -> JsonValue {
+    match *json {
+        Json::I64(n) => JsonValue::Number(n as f64),
+        Json::U64(n) => JsonValue::Number(n as f64),
+        Json::F64(n) => JsonValue::Number(n),
+        Json::String(ref s) => JsonValue::String(s.clone()),
+        Json::Boolean(true) => JsonValue::True,
+        Json::Boolean(false) => JsonValue::False,
+        Json::Array(ref vec) => JsonValue::Array(vec.iter().map(to_json_value).collect()),
+        Json::Object(ref map) => JsonValue::Object(
+            map.iter()
+                .map(|(key, value)| (key.clone(), to_json_value(value)))
+                .collect(),
+        ),
+        Json::Null => JsonValue::Null,
+    }
+}
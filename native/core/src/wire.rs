@@ -0,0 +1,528 @@
+// The messages exchanged with the serving threads, by clients in this process
+// as well as in others through a daemon. Each frame on the socket starts with
+// a header:
+//
+// - the version of the protocol (1 byte)
+// - the type of the message (1 byte)
+// - the id of the request, a response has the id of its request (4 bytes)
+// - the length of the payload (4 bytes)
+//
+// The payload are the fields of the message. Numbers are big endian and
+// strings are prefixed by their length in bytes.
+
+use std::io::{self, Read, Write};
+use std::str;
+
+use noise_search::json_value::JsonValue;
+
+use {AddOptions, DropOptions, IndexOpenOptions, Message};
+
+// Frames of other versions are rejected
+pub const PROTOCOL_VERSION: u8 = 1;
+
+const HEADER_SIZE: usize = 10;
+
+// Larger payloads are rejected, whether they're read or written
+const MAX_PAYLOAD_SIZE: u32 = 64 << 20;
+
+// JSON values nested deeper are rejected rather than overflowing the stack
+const MAX_JSON_DEPTH: usize = 512;
+
+// The message types. Requests use the same numbers as the Javascript side.
+const OPEN_INDEX: u8 = 0;
+const DROP_INDEX: u8 = 1;
+const ADD: u8 = 2;
+const DELETE: u8 = 3;
+const QUERY: u8 = 4;
+const CLOSE: u8 = 5;
+const REGISTER_QUERY: u8 = 6;
+const UNREGISTER_QUERY: u8 = 7;
+const INFO: u8 = 8;
+const SCHEMA: u8 = 9;
+const BACKUP: u8 = 10;
+const RESTORE: u8 = 11;
+const LIST_BACKUPS: u8 = 12;
+const MEMORY_USAGE: u8 = 13;
+const COMPACT: u8 = 14;
+const LIST_INDEXES: u8 = 15;
+const COPY_INDEX: u8 = 16;
+const RENAME_INDEX: u8 = 17;
+const RESPONSE_OK: u8 = 64;
+const RESPONSE_ERROR: u8 = 65;
+
+// The types of JSON values
+const JSON_NULL: u8 = 0;
+const JSON_TRUE: u8 = 1;
+const JSON_FALSE: u8 = 2;
+const JSON_NUMBER: u8 = 3;
+const JSON_STRING: u8 = 4;
+const JSON_ARRAY: u8 = 5;
+const JSON_OBJECT: u8 = 6;
+
+pub struct Frame {
+    pub msg_type: u8,
+    pub request_id: u32,
+    pub payload: Vec<u8>,
+}
+
+impl Frame {
+    // Fails if the message is too large for a frame.
+    pub fn new(request_id: u32, msg: &Message) -> Result<Frame, String> {
+        let (msg_type, payload) = encode_message(msg)?;
+        Ok(Frame {
+            msg_type,
+            request_id,
+            payload,
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(HEADER_SIZE + self.payload.len());
+        bytes.push(PROTOCOL_VERSION);
+        bytes.push(self.msg_type);
+        bytes.extend_from_slice(&self.request_id.to_be_bytes());
+        // the payload isn't larger than MAX_PAYLOAD_SIZE, see encode_message
+        bytes.extend_from_slice(&(self.payload.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(&self.payload);
+        bytes
+    }
+
+    pub fn message(&self) -> Result<Message, String> {
+        decode_message(self.msg_type, &self.payload)
+    }
+}
+
+pub fn write_frame<W: Write>(writer: &mut W, frame: &Frame) -> io::Result<()> {
+    writer.write_all(&frame.to_bytes())?;
+    writer.flush()
+}
+
+// Returns the next frame, or None if the stream ended before a new frame
+// started.
+pub fn read_frame<R: Read>(reader: &mut R) -> io::Result<Option<Frame>> {
+    let mut header = [0; HEADER_SIZE];
+    let mut read = 0;
+    while read < header.len() {
+        match reader.read(&mut header[read..]) {
+            Ok(0) if read == 0 => return Ok(None),
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(size) => read += size,
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    if header[0] != PROTOCOL_VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "unsupported protocol version {}, expected {}",
+                header[0], PROTOCOL_VERSION
+            ),
+        ));
+    }
+    let mut request_id = [0; 4];
+    request_id.copy_from_slice(&header[2..6]);
+    let mut len = [0; 4];
+    len.copy_from_slice(&header[6..10]);
+    let len = u32::from_be_bytes(len);
+    if len > MAX_PAYLOAD_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("payload of {} bytes is too large", len),
+        ));
+    }
+    // the length isn't trusted to reserve memory, the payload may be missing
+    let mut payload = Vec::new();
+    reader
+        .by_ref()
+        .take(u64::from(len))
+        .read_to_end(&mut payload)?;
+    if payload.len() != len as usize {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(Some(Frame {
+        msg_type: header[1],
+        request_id: u32::from_be_bytes(request_id),
+        payload,
+    }))
+}
+
+fn encode_message(msg: &Message) -> Result<(u8, Vec<u8>), String> {
+    let mut encoder = Encoder {
+        msg_type: 0,
+        buf: Vec::new(),
+        too_large: false,
+    };
+    match *msg {
+        Message::OpenIndex(ref name, ref options) => {
+            encoder.msg_type(OPEN_INDEX);
+            encoder.str(name);
+            encoder.bool(options.create_if_missing);
+            encoder.bool(options.error_if_exists);
+            encoder.bool(options.read_only);
+            encoder.opt_u64(options.compact_deleted_threshold.map(|count| count as u64));
+            encoder.bool(options.in_memory);
+            encoder.bool(options.temp);
+        }
+        Message::DropIndex(ref name, ref options) => {
+            encoder.msg_type(DROP_INDEX);
+            encoder.str(name);
+            encoder.bool(options.dry_run);
+            encoder.bool(options.force);
+        }
+        Message::Add(ref docs, ref options) => {
+            encoder.msg_type(ADD);
+            encoder.strs(docs);
+            encoder.bool(options.percolate);
+        }
+        Message::Delete(ref ids) => {
+            encoder.msg_type(DELETE);
+            encoder.strs(ids);
+        }
+        Message::Query(ref query, ref params) => {
+            encoder.msg_type(QUERY);
+            encoder.str(query);
+            encoder.opt_str(params);
+        }
+        Message::RegisterQuery(ref name, ref query, ref params) => {
+            encoder.msg_type(REGISTER_QUERY);
+            encoder.str(name);
+            encoder.str(query);
+            encoder.opt_str(params);
+        }
+        Message::UnregisterQuery(ref name) => {
+            encoder.msg_type(UNREGISTER_QUERY);
+            encoder.str(name);
+        }
+        Message::Info => encoder.msg_type(INFO),
+        Message::Schema(sample_size) => {
+            encoder.msg_type(SCHEMA);
+            encoder.opt_u64(sample_size.map(|size| size as u64));
+        }
+        Message::Backup(ref backup_dir, keep) => {
+            encoder.msg_type(BACKUP);
+            encoder.str(backup_dir);
+            encoder.opt_u64(keep.map(|keep| keep as u64));
+        }
+        Message::Restore(ref backup_dir, ref name, backup_id) => {
+            encoder.msg_type(RESTORE);
+            encoder.str(backup_dir);
+            encoder.str(name);
+            encoder.opt_u64(backup_id.map(|id| id as u64));
+        }
+        Message::ListBackups(ref backup_dir) => {
+            encoder.msg_type(LIST_BACKUPS);
+            encoder.str(backup_dir);
+        }
+        Message::MemoryUsage => encoder.msg_type(MEMORY_USAGE),
+        Message::Compact => encoder.msg_type(COMPACT),
+        Message::ListIndexes(ref dir) => {
+            encoder.msg_type(LIST_INDEXES);
+            encoder.str(dir);
+        }
+        Message::CopyIndex(ref src, ref dest) => {
+            encoder.msg_type(COPY_INDEX);
+            encoder.str(src);
+            encoder.str(dest);
+        }
+        Message::RenameIndex(ref src, ref dest) => {
+            encoder.msg_type(RENAME_INDEX);
+            encoder.str(src);
+            encoder.str(dest);
+        }
+        Message::Close => encoder.msg_type(CLOSE),
+        Message::ResponseOk(ref json) => {
+            encoder.msg_type(RESPONSE_OK);
+            encoder.json(json);
+        }
+        Message::ResponseError(ref msg) => {
+            encoder.msg_type(RESPONSE_ERROR);
+            encoder.str(msg);
+        }
+    }
+    if encoder.too_large || encoder.buf.len() > MAX_PAYLOAD_SIZE as usize {
+        return Err(format!(
+            "message is larger than the limit of {} bytes",
+            MAX_PAYLOAD_SIZE
+        ));
+    }
+    Ok((encoder.msg_type, encoder.buf))
+}
+
+fn decode_message(msg_type: u8, payload: &[u8]) -> Result<Message, String> {
+    let mut decoder = Decoder {
+        buf: payload,
+        pos: 0,
+        depth: 0,
+    };
+    let msg = match msg_type {
+        OPEN_INDEX => {
+            let name = decoder.str()?;
+            let options = IndexOpenOptions {
+                create_if_missing: decoder.bool()?,
+                error_if_exists: decoder.bool()?,
+                read_only: decoder.bool()?,
+                compact_deleted_threshold: decoder.opt_u64()?.map(|count| count as usize),
+                in_memory: decoder.bool()?,
+                temp: decoder.bool()?,
+            };
+            Message::OpenIndex(name, options)
+        }
+        DROP_INDEX => {
+            let name = decoder.str()?;
+            let options = DropOptions {
+                dry_run: decoder.bool()?,
+                force: decoder.bool()?,
+            };
+            Message::DropIndex(name, options)
+        }
+        ADD => {
+            let docs = decoder.strs()?;
+            let options = AddOptions {
+                percolate: decoder.bool()?,
+            };
+            Message::Add(docs, options)
+        }
+        DELETE => Message::Delete(decoder.strs()?),
+        QUERY => Message::Query(decoder.str()?, decoder.opt_str()?),
+        REGISTER_QUERY => {
+            Message::RegisterQuery(decoder.str()?, decoder.str()?, decoder.opt_str()?)
+        }
+        UNREGISTER_QUERY => Message::UnregisterQuery(decoder.str()?),
+        INFO => Message::Info,
+        SCHEMA => Message::Schema(decoder.opt_u64()?.map(|size| size as usize)),
+        BACKUP => Message::Backup(decoder.str()?, decoder.opt_u64()?.map(|keep| keep as u32)),
+        RESTORE => Message::Restore(
+            decoder.str()?,
+            decoder.str()?,
+            decoder.opt_u64()?.map(|id| id as u32),
+        ),
+        LIST_BACKUPS => Message::ListBackups(decoder.str()?),
+        MEMORY_USAGE => Message::MemoryUsage,
+        COMPACT => Message::Compact,
+        LIST_INDEXES => Message::ListIndexes(decoder.str()?),
+        COPY_INDEX => Message::CopyIndex(decoder.str()?, decoder.str()?),
+        RENAME_INDEX => Message::RenameIndex(decoder.str()?, decoder.str()?),
+        CLOSE => Message::Close,
+        RESPONSE_OK => Message::ResponseOk(decoder.json()?),
+        RESPONSE_ERROR => Message::ResponseError(decoder.str()?),
+        other => return Err(format!("unknown message type {}", other)),
+    };
+    if decoder.pos != payload.len() {
+        return Err("unexpected data after the message".to_string());
+    }
+    Ok(msg)
+}
+
+struct Encoder {
+    msg_type: u8,
+    buf: Vec<u8>,
+    // set if a length doesn't fit into the payload
+    too_large: bool,
+}
+
+impl Encoder {
+    fn msg_type(&mut self, msg_type: u8) {
+        self.msg_type = msg_type;
+    }
+
+    fn u8(&mut self, value: u8) {
+        self.buf.push(value);
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.buf.extend_from_slice(&value.to_be_bytes());
+    }
+
+    // A length larger than a payload can be makes the message fail to encode.
+    fn len(&mut self, len: usize) {
+        if len > MAX_PAYLOAD_SIZE as usize {
+            self.too_large = true;
+        }
+        self.u32(len as u32);
+    }
+
+    fn bool(&mut self, value: bool) {
+        self.u8(value as u8);
+    }
+
+    fn opt_u64(&mut self, value: Option<u64>) {
+        match value {
+            Some(value) => {
+                self.u8(1);
+                self.buf.extend_from_slice(&value.to_be_bytes());
+            }
+            None => self.u8(0),
+        }
+    }
+
+    fn str(&mut self, value: &str) {
+        self.len(value.len());
+        self.buf.extend_from_slice(value.as_bytes());
+    }
+
+    fn opt_str(&mut self, value: &Option<String>) {
+        match *value {
+            Some(ref value) => {
+                self.u8(1);
+                self.str(value);
+            }
+            None => self.u8(0),
+        }
+    }
+
+    fn strs(&mut self, values: &[String]) {
+        self.len(values.len());
+        for value in values {
+            self.str(value);
+        }
+    }
+
+    fn json(&mut self, json: &JsonValue) {
+        match *json {
+            JsonValue::Null => self.u8(JSON_NULL),
+            JsonValue::True => self.u8(JSON_TRUE),
+            JsonValue::False => self.u8(JSON_FALSE),
+            JsonValue::Number(n) => {
+                self.u8(JSON_NUMBER);
+                self.buf.extend_from_slice(&n.to_bits().to_be_bytes());
+            }
+            JsonValue::String(ref s) => {
+                self.u8(JSON_STRING);
+                self.str(s);
+            }
+            JsonValue::Array(ref vec) => {
+                self.u8(JSON_ARRAY);
+                self.len(vec.len());
+                for value in vec {
+                    self.json(value);
+                }
+            }
+            JsonValue::Object(ref vec) => {
+                self.u8(JSON_OBJECT);
+                self.len(vec.len());
+                for (key, value) in vec {
+                    self.str(key);
+                    self.json(value);
+                }
+            }
+        }
+    }
+}
+
+struct Decoder<'a> {
+    buf: &'a [u8],
+    pos: usize,
+    // how deep the JSON value being decoded is nested
+    depth: usize,
+}
+
+impl<'a> Decoder<'a> {
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], String> {
+        if self.buf.len() - self.pos < len {
+            return Err("message is truncated".to_string());
+        }
+        let bytes = &self.buf[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(self.bytes(4)?);
+        Ok(u32::from_be_bytes(bytes))
+    }
+
+    fn u64(&mut self) -> Result<u64, String> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.bytes(8)?);
+        Ok(u64::from_be_bytes(bytes))
+    }
+
+    fn bool(&mut self) -> Result<bool, String> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            other => Err(format!("invalid boolean {}", other)),
+        }
+    }
+
+    fn opt_u64(&mut self) -> Result<Option<u64>, String> {
+        if self.bool()? {
+            Ok(Some(self.u64()?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn str(&mut self) -> Result<String, String> {
+        let len = self.u32()? as usize;
+        match str::from_utf8(self.bytes(len)?) {
+            Ok(s) => Ok(s.to_string()),
+            Err(_) => Err("string isn't valid UTF-8".to_string()),
+        }
+    }
+
+    fn opt_str(&mut self) -> Result<Option<String>, String> {
+        if self.bool()? {
+            Ok(Some(self.str()?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn strs(&mut self) -> Result<Vec<String>, String> {
+        let len = self.u32()? as usize;
+        // don't trust the length to reserve memory, the strings may be missing
+        let mut values = Vec::new();
+        for _ in 0..len {
+            values.push(self.str()?);
+        }
+        Ok(values)
+    }
+
+    fn json(&mut self) -> Result<JsonValue, String> {
+        if self.depth == MAX_JSON_DEPTH {
+            return Err(format!(
+                "JSON is nested deeper than {} levels",
+                MAX_JSON_DEPTH
+            ));
+        }
+        self.depth += 1;
+        let json = self.json_value();
+        self.depth -= 1;
+        json
+    }
+
+    fn json_value(&mut self) -> Result<JsonValue, String> {
+        match self.u8()? {
+            JSON_NULL => Ok(JsonValue::Null),
+            JSON_TRUE => Ok(JsonValue::True),
+            JSON_FALSE => Ok(JsonValue::False),
+            JSON_NUMBER => Ok(JsonValue::Number(f64::from_bits(self.u64()?))),
+            JSON_STRING => Ok(JsonValue::String(self.str()?)),
+            JSON_ARRAY => {
+                let len = self.u32()? as usize;
+                let mut vec = Vec::new();
+                for _ in 0..len {
+                    vec.push(self.json()?);
+                }
+                Ok(JsonValue::Array(vec))
+            }
+            JSON_OBJECT => {
+                let len = self.u32()? as usize;
+                let mut vec = Vec::new();
+                for _ in 0..len {
+                    let key = self.str()?;
+                    vec.push((key, self.json()?));
+                }
+                Ok(JsonValue::Object(vec))
+            }
+            other => Err(format!("unknown JSON type {}", other)),
+        }
+    }
+}
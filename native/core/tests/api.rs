@@ -0,0 +1,334 @@
+extern crate noise_core;
+
+use std::fs;
+use std::path::Path;
+
+use noise_core::wire::{self, Frame};
+use noise_core::{
+    open_instance, process_message, process_request, AddOptions, DropOptions, IndexOpenOptions,
+    JsonValue, Message, OpenedIndexCleanupGuard,
+};
+
+// A path for the index `name` that doesn't exist yet.
+fn index_path(name: &str) -> String {
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("api");
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join(name);
+    let _ = fs::remove_dir_all(&path);
+    path.to_string_lossy().into_owned()
+}
+
+fn create(name: &str) -> OpenedIndexCleanupGuard {
+    let options = IndexOpenOptions {
+        create_if_missing: true,
+        ..Default::default()
+    };
+    open_instance(name, &options).unwrap()
+}
+
+fn ok(resp: Message) -> JsonValue {
+    match resp {
+        Message::ResponseOk(json) => json,
+        Message::ResponseError(msg) => panic!("error response: {}", msg),
+        _ => panic!("not a response"),
+    }
+}
+
+fn error(resp: Message) -> String {
+    match resp {
+        Message::ResponseError(msg) => msg,
+        Message::ResponseOk(json) => panic!("ok response: {:?}", json),
+        _ => panic!("not a response"),
+    }
+}
+
+fn strings(values: &[&str]) -> JsonValue {
+    JsonValue::Array(
+        values
+            .iter()
+            .map(|value| JsonValue::String(value.to_string()))
+            .collect(),
+    )
+}
+
+fn add(index: &mut OpenedIndexCleanupGuard, docs: &[&str]) -> JsonValue {
+    let docs = docs.iter().map(|doc| doc.to_string()).collect();
+    ok(process_message(
+        index,
+        Message::Add(docs, AddOptions::default()),
+    ))
+}
+
+// The results of the query, in the order they are returned to clients.
+fn query(index: &mut OpenedIndexCleanupGuard, query: &str, params: Option<&str>) -> JsonValue {
+    let msg = Message::Query(query.to_string(), params.map(|params| params.to_string()));
+    match ok(process_message(index, msg)) {
+        JsonValue::Array(mut results) => {
+            results.reverse();
+            JsonValue::Array(results)
+        }
+        _ => panic!("query results aren't an array"),
+    }
+}
+
+fn info_number(index: &mut OpenedIndexCleanupGuard, key: &str) -> f64 {
+    match ok(process_message(index, Message::Info)) {
+        JsonValue::Object(info) => match info.into_iter().find(|(name, _)| name == key) {
+            Some((_, JsonValue::Number(number))) => number,
+            _ => panic!("no number {} in the info", key),
+        },
+        _ => panic!("info isn't an object"),
+    }
+}
+
+#[test]
+fn add_query_delete() {
+    let name = index_path("add_query_delete");
+    let mut index = create(&name);
+    assert_eq!(
+        add(
+            &mut index,
+            &[
+                r#"{"_id": "a", "foo": "bar"}"#,
+                r#"{"_id": "b", "foo": "baz"}"#,
+                r#"{"_id": "c", "foo": "baz"}"#,
+            ]
+        ),
+        strings(&["a", "b", "c"])
+    );
+    assert_eq!(
+        query(&mut index, "find {foo: == @foo}", Some(r#"{"foo": "baz"}"#)),
+        strings(&["b", "c"])
+    );
+    let deleted = process_message(
+        &mut index,
+        Message::Delete(vec!["b".to_string(), "x".to_string()]),
+    );
+    assert_eq!(
+        ok(deleted),
+        JsonValue::Array(vec![JsonValue::True, JsonValue::False])
+    );
+    assert_eq!(query(&mut index, "find {}", None), strings(&["a", "c"]));
+    assert_eq!(info_number(&mut index, "docCount"), 2.0);
+    assert!(error(process_message(
+        &mut index,
+        Message::Query("find {".to_string(), None)
+    ))
+    .starts_with("Parse error"));
+}
+
+#[test]
+fn instances_share_the_index() {
+    let name = index_path("instances_share_the_index");
+    let mut first = create(&name);
+    let mut second = open_instance(&name, &IndexOpenOptions::default()).unwrap();
+    add(&mut first, &[r#"{"_id": "a"}"#]);
+    assert_eq!(query(&mut second, "find {}", None), strings(&["a"]));
+    assert_eq!(info_number(&mut second, "openCount"), 2.0);
+    drop(first);
+    assert_eq!(info_number(&mut second, "openCount"), 1.0);
+}
+
+#[test]
+fn memory_indexes_are_separate() {
+    let options = IndexOpenOptions {
+        in_memory: true,
+        ..Default::default()
+    };
+    let mut first = open_instance(":memory:", &options).unwrap();
+    let mut second = open_instance(":memory:", &options).unwrap();
+    add(&mut first, &[r#"{"_id": "a"}"#]);
+    assert_eq!(query(&mut first, "find {}", None), strings(&["a"]));
+    assert_eq!(query(&mut second, "find {}", None), strings(&[]));
+}
+
+#[test]
+fn named_memory_indexes_are_separate_from_anonymous_ones() {
+    let options = IndexOpenOptions {
+        in_memory: true,
+        ..Default::default()
+    };
+    let mut anonymous = open_instance(":memory:", &options).unwrap();
+    add(&mut anonymous, &[r#"{"_id": "anonymous"}"#]);
+    // the anonymous indexes are numbered, a named one with the same number
+    // must be another index
+    let usage = ok(process_request(Message::MemoryUsage));
+    let numbers: Vec<String> = match usage {
+        JsonValue::Object(fields) => fields
+            .into_iter()
+            .filter_map(|(key, value)| match value {
+                JsonValue::Array(indexes) if key == "indexes" => Some(indexes),
+                _ => None,
+            })
+            .flatten()
+            .filter_map(|index| match index {
+                JsonValue::Object(fields) => fields.into_iter().find_map(|field| match field {
+                    (key, JsonValue::String(name)) if key == "name" => {
+                        name.rsplit(':').next().map(|number| number.to_string())
+                    }
+                    _ => None,
+                }),
+                _ => None,
+            })
+            .filter(|number| number.parse::<usize>().is_ok())
+            .collect(),
+        _ => panic!("not an object"),
+    };
+    assert!(!numbers.is_empty());
+    for number in numbers {
+        let mut named = open_instance(&number, &options).unwrap();
+        assert_eq!(query(&mut named, "find {}", None), strings(&[]));
+    }
+}
+
+#[test]
+fn drop_needs_closed_index() {
+    let name = index_path("drop_needs_closed_index");
+    let index = create(&name);
+    let drop_msg = || Message::DropIndex(name.clone(), DropOptions::default());
+    assert_eq!(
+        error(process_request(drop_msg())),
+        "Index instances still open"
+    );
+    drop(index);
+    assert_eq!(ok(process_request(drop_msg())), JsonValue::True);
+    assert!(!Path::new(&name).exists());
+}
+
+#[test]
+fn forced_drop_leaves_direct_instances() {
+    let name = index_path("forced_drop_leaves_direct_instances");
+    let mut index = create(&name);
+    let force = DropOptions {
+        force: true,
+        ..Default::default()
+    };
+    assert_eq!(
+        error(process_request(Message::DropIndex(name.clone(), force))),
+        "Index instances still open"
+    );
+    // the instance wasn't closed
+    add(&mut index, &[r#"{"_id": "a"}"#]);
+    drop(index);
+    let force = DropOptions {
+        force: true,
+        ..Default::default()
+    };
+    assert_eq!(
+        ok(process_request(Message::DropIndex(name.clone(), force))),
+        JsonValue::True
+    );
+    assert!(!Path::new(&name).exists());
+}
+
+#[test]
+fn instance_requests_need_an_instance() {
+    assert_eq!(
+        error(process_request(Message::Info)),
+        "An index must be opened first"
+    );
+}
+
+#[test]
+fn instances_only_take_instance_requests() {
+    let name = index_path("instances_only_take_instance_requests");
+    let mut index = create(&name);
+    error(process_message(&mut index, Message::Close));
+    error(process_message(&mut index, Message::MemoryUsage));
+    error(process_message(
+        &mut index,
+        Message::ResponseOk(JsonValue::True),
+    ));
+    // the instance goes on working
+    add(&mut index, &[r#"{"_id": "a"}"#]);
+    drop(index);
+    let _ = fs::remove_dir_all(&name);
+}
+
+#[test]
+fn frames_round_trip() {
+    let msg = Message::Query(
+        "find {foo: == @foo}".to_string(),
+        Some(r#"{"foo": "bar"}"#.to_string()),
+    );
+    let bytes = Frame::new(7, &msg).unwrap().to_bytes();
+    let frame = wire::read_frame(&mut &bytes[..]).unwrap().unwrap();
+    assert_eq!(frame.request_id, 7);
+    match frame.message().unwrap() {
+        Message::Query(query, params) => {
+            assert_eq!(query, "find {foo: == @foo}");
+            assert_eq!(params.unwrap(), r#"{"foo": "bar"}"#);
+        }
+        _ => panic!("not a query"),
+    }
+    // a frame cut short is an error rather than a message
+    assert!(wire::read_frame(&mut &bytes[..bytes.len() - 1]).is_err());
+}
+
+#[test]
+fn frames_are_limited() {
+    // a length beyond the limit is refused before reading the payload
+    let header = [
+        wire::PROTOCOL_VERSION,
+        4,
+        0,
+        0,
+        0,
+        1,
+        0xff,
+        0xff,
+        0xff,
+        0xff,
+    ];
+    assert!(wire::read_frame(&mut &header[..]).is_err());
+    // a message too large to encode is an error instead of a truncated length
+    let query = "a".repeat(64 << 20);
+    assert!(Frame::new(1, &Message::Query(query, None)).is_err());
+    // so is JSON nested too deep to decode
+    let mut json = JsonValue::Null;
+    for _ in 0..1000 {
+        json = JsonValue::Array(vec![json]);
+    }
+    let bytes = Frame::new(1, &Message::ResponseOk(json))
+        .unwrap()
+        .to_bytes();
+    let frame = wire::read_frame(&mut &bytes[..]).unwrap().unwrap();
+    assert!(frame.message().is_err());
+}
+
+#[test]
+fn json_rpc() {
+    let name = index_path("json_rpc");
+    let requests = [
+        format!(
+            r#"{{"jsonrpc": "2.0", "id": 1, "method": "open", "params": {{"name": "{}",
+                "options": {{"createIfMissing": true}}}}}}"#,
+            name
+        ),
+        r#"{"jsonrpc": "2.0", "id": 2, "method": "add", "params": {"handle": 1,
+            "docs": {"_id": "a"}}}"#
+            .to_string(),
+        r#"{"jsonrpc": "2.0", "id": 3, "method": "query", "params": {"handle": 1,
+            "query": "find {}"}}"#
+            .to_string(),
+    ];
+    // each request is on a line of its own
+    let input: Vec<String> = requests
+        .iter()
+        .map(|request| request.replace('\n', " "))
+        .collect();
+    let input = input.join("\n");
+    let mut output = Vec::new();
+    noise_core::rpc::serve(input.as_bytes(), &mut output).unwrap();
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        concat!(
+            r#"{"jsonrpc":"2.0","id":1,"result":1}"#,
+            "\n",
+            r#"{"jsonrpc":"2.0","id":2,"result":["a"]}"#,
+            "\n",
+            r#"{"jsonrpc":"2.0","id":3,"result":["a"]}"#,
+            "\n"
+        )
+    );
+}
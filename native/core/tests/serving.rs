@@ -0,0 +1,377 @@
+// Tests of the serving layer: instances served to clients connected over a
+// socket, like those of the Node.js binding, and instances used directly. All
+// tests share the instances of this process, so each uses indexes of its own.
+
+extern crate noise_core;
+
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use noise_core::wire::{self, Frame};
+use noise_core::{
+    open_instance, process_message, process_request, AddOptions, DropOptions, IndexOpenOptions,
+    JsonValue, Message, OpenedIndexCleanupGuard,
+};
+
+// A directory of its own for the test `name`, with nothing in it.
+fn test_dir(name: &str) -> PathBuf {
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR"))
+        .join("serving")
+        .join(name);
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+// Starts a daemon for the test, returns the path of its socket.
+fn start_daemon(dir: &Path) -> PathBuf {
+    let path = dir.join("noise.sock");
+    noise_core::start_daemon(path.clone()).unwrap();
+    path
+}
+
+fn create_options() -> IndexOpenOptions {
+    IndexOpenOptions {
+        create_if_missing: true,
+        ..Default::default()
+    }
+}
+
+// A client connected to the daemon, sending frames like the Javascript side.
+struct Client {
+    stream: UnixStream,
+    next_request_id: u32,
+}
+
+impl Client {
+    fn connect(socket: &Path) -> Client {
+        Client {
+            stream: UnixStream::connect(socket).unwrap(),
+            next_request_id: 0,
+        }
+    }
+
+    // Connects and opens an instance of the index.
+    fn open(socket: &Path, name: &str) -> Client {
+        let mut client = Client::connect(socket);
+        let opened = client.request(Message::OpenIndex(name.to_string(), create_options()));
+        assert_eq!(ok(opened.unwrap()), JsonValue::True);
+        client
+    }
+
+    // Returns the response, None if the connection was closed instead.
+    fn request(&mut self, msg: Message) -> Option<Message> {
+        let request_id = self.next_request_id;
+        self.next_request_id += 1;
+        let frame = Frame::new(request_id, &msg).unwrap();
+        if wire::write_frame(&mut self.stream, &frame).is_err() {
+            return None;
+        }
+        let frame = wire::read_frame(&mut self.stream).ok()??;
+        assert_eq!(frame.request_id, request_id);
+        Some(frame.message().unwrap())
+    }
+
+    // Closes the instance and waits until the server hung up.
+    fn close(mut self) {
+        assert!(self.request(Message::Close).is_none());
+    }
+}
+
+fn ok(resp: Message) -> JsonValue {
+    match resp {
+        Message::ResponseOk(json) => json,
+        Message::ResponseError(msg) => panic!("error response: {}", msg),
+        _ => panic!("not a response"),
+    }
+}
+
+fn error(resp: Message) -> String {
+    match resp {
+        Message::ResponseError(msg) => msg,
+        Message::ResponseOk(json) => panic!("ok response: {:?}", json),
+        _ => panic!("not a response"),
+    }
+}
+
+fn add(id: &str, client: usize) -> Message {
+    let doc = format!(r#"{{"_id": "{}", "client": {}}}"#, id, client);
+    Message::Add(vec![doc], AddOptions::default())
+}
+
+fn query_ids(resp: Message) -> Vec<String> {
+    match ok(resp) {
+        JsonValue::Array(results) => {
+            let mut ids: Vec<String> = results
+                .into_iter()
+                .map(|result| match result {
+                    JsonValue::String(id) => id,
+                    _ => panic!("not an id"),
+                })
+                .collect();
+            ids.sort();
+            ids
+        }
+        _ => panic!("query results aren't an array"),
+    }
+}
+
+fn open_count(index: &mut OpenedIndexCleanupGuard) -> usize {
+    match ok(process_message(index, Message::Info)) {
+        JsonValue::Object(info) => match info.into_iter().find(|(key, _)| key == "openCount") {
+            Some((_, JsonValue::Number(count))) => count as usize,
+            _ => panic!("no open count in the info"),
+        },
+        _ => panic!("info isn't an object"),
+    }
+}
+
+// Waits until the open count of the index is `count`. Instances served over a
+// socket are closed by their thread once it noticed the client is gone.
+fn wait_for_open_count(index: &mut OpenedIndexCleanupGuard, count: usize) {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while open_count(index) != count {
+        assert!(
+            Instant::now() < deadline,
+            "open count stayed {} rather than {}",
+            open_count(index),
+            count
+        );
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+fn drop_index(name: &str, force: bool) -> Message {
+    let options = DropOptions {
+        dry_run: false,
+        force,
+    };
+    process_request(Message::DropIndex(name.to_string(), options))
+}
+
+#[test]
+fn daemon_socket_is_private() {
+    let dir = test_dir("daemon_socket_is_private");
+    let socket = start_daemon(&dir);
+    let mode = fs::metadata(&socket).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o600);
+    // only the socket is left in the directory
+    let entries: Vec<_> = fs::read_dir(&dir).unwrap().collect();
+    assert_eq!(entries.len(), 1);
+    UnixStream::connect(&socket).unwrap();
+}
+
+#[test]
+fn concurrent_clients() {
+    let dir = test_dir("concurrent_clients");
+    let socket = start_daemon(&dir);
+    // half of the clients share an index, the others have one each
+    let threads: Vec<_> = (0..16)
+        .map(|client| {
+            let socket = socket.clone();
+            let name = if client % 2 == 0 {
+                dir.join("shared")
+            } else {
+                dir.join(format!("own{}", client))
+            };
+            let name = name.to_string_lossy().into_owned();
+            thread::spawn(move || {
+                let mut client_conn = Client::open(&socket, &name);
+                let ids: Vec<String> = (0..10).map(|n| format!("c{}-{}", client, n)).collect();
+                for id in &ids {
+                    ok(client_conn.request(add(id, client)).unwrap());
+                }
+                let deleted = client_conn.request(Message::Delete(ids[5..].to_vec()));
+                assert_eq!(
+                    ok(deleted.unwrap()),
+                    JsonValue::Array(vec![JsonValue::True; 5])
+                );
+                let query = Message::Query(
+                    "find {client: == @client}".to_string(),
+                    Some(format!(r#"{{"client": {}}}"#, client)),
+                );
+                let found = query_ids(client_conn.request(query).unwrap());
+                assert_eq!(found, ids[..5].to_vec());
+                client_conn.close();
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    // all instances were closed, so the indexes can be dropped
+    let shared = dir.join("shared").to_string_lossy().into_owned();
+    assert_eq!(ok(drop_index(&shared, false)), JsonValue::True);
+    for client in (1..16).step_by(2) {
+        let own = dir.join(format!("own{}", client));
+        assert_eq!(
+            ok(drop_index(&own.to_string_lossy(), false)),
+            JsonValue::True
+        );
+    }
+}
+
+#[test]
+fn open_count_follows_instances() {
+    let dir = test_dir("open_count_follows_instances");
+    let socket = start_daemon(&dir);
+    let name = dir.join("index").to_string_lossy().into_owned();
+    let mut direct = open_instance(&name, &create_options()).unwrap();
+    assert_eq!(open_count(&mut direct), 1);
+
+    let mut clients: Vec<Client> = (0..4).map(|_| Client::open(&socket, &name)).collect();
+    assert_eq!(open_count(&mut direct), 5);
+
+    // closed by the client
+    clients.pop().unwrap().close();
+    assert_eq!(open_count(&mut direct), 4);
+    // closed once the serving thread notices the client hung up
+    drop(clients.pop());
+    wait_for_open_count(&mut direct, 3);
+
+    // a failed open doesn't count
+    let mut failed = Client::connect(&socket);
+    let options = IndexOpenOptions {
+        error_if_exists: true,
+        ..Default::default()
+    };
+    let opened = failed.request(Message::OpenIndex(name.clone(), options));
+    assert_eq!(error(opened.unwrap()), "Index already exists");
+    assert_eq!(open_count(&mut direct), 3);
+
+    // instances used directly count the same
+    let other = open_instance(&name, &IndexOpenOptions::default()).unwrap();
+    assert_eq!(open_count(&mut direct), 4);
+    drop(other);
+    assert_eq!(open_count(&mut direct), 3);
+
+    for client in clients {
+        client.close();
+    }
+    assert_eq!(open_count(&mut direct), 1);
+    drop(direct);
+    // the index is closed with its last instance
+    assert_eq!(ok(drop_index(&name, false)), JsonValue::True);
+}
+
+#[test]
+fn drop_while_open_is_rejected() {
+    let dir = test_dir("drop_while_open_is_rejected");
+    let socket = start_daemon(&dir);
+    let name = dir.join("index").to_string_lossy().into_owned();
+    let mut client = Client::open(&socket, &name);
+    ok(client.request(add("a", 0)).unwrap());
+
+    // neither directly nor by a client
+    assert_eq!(
+        error(drop_index(&name, false)),
+        "Index instances still open"
+    );
+    let mut dropper = Client::connect(&socket);
+    let dropped = dropper.request(Message::DropIndex(name.clone(), DropOptions::default()));
+    assert_eq!(error(dropped.unwrap()), "Index instances still open");
+    assert!(Path::new(&name).exists());
+
+    // the instance is unaffected
+    let query = Message::Query("find {}".to_string(), None);
+    assert_eq!(query_ids(client.request(query).unwrap()), vec!["a"]);
+
+    // a forced drop closes the instance first, the client is hung up on
+    assert_eq!(ok(drop_index(&name, true)), JsonValue::True);
+    assert!(!Path::new(&name).exists());
+    assert!(client.request(add("b", 0)).is_none());
+}
+
+#[test]
+fn responses_from_clients_are_rejected() {
+    let dir = test_dir("responses_from_clients_are_rejected");
+    let socket = start_daemon(&dir);
+    let name = dir.join("index").to_string_lossy().into_owned();
+    let mut client = Client::connect(&socket);
+    let resp = client.request(Message::ResponseOk(JsonValue::True));
+    assert_eq!(error(resp.unwrap()), "Clients can't send responses");
+
+    // neither before nor after opening an instance
+    let mut client = Client::open(&socket, &name);
+    let resp = client.request(Message::ResponseError("oops".to_string()));
+    assert_eq!(error(resp.unwrap()), "Clients can't send responses");
+    ok(client.request(add("a", 0)).unwrap());
+    client.close();
+    assert_eq!(ok(drop_index(&name, false)), JsonValue::True);
+}
+
+#[test]
+fn panic_in_instance_leaves_others_working() {
+    let dir = test_dir("panic_in_instance_leaves_others_working");
+    let socket = start_daemon(&dir);
+    let name = dir.join("index").to_string_lossy().into_owned();
+    let mut survivor = open_instance(&name, &create_options()).unwrap();
+    let mut client = Client::open(&socket, &name);
+
+    // an instance that panics while it has the index locked
+    let panicking = open_instance(&name, &IndexOpenOptions::default()).unwrap();
+    let result = thread::spawn(move || {
+        let _locked = panicking.write();
+        panic!("instance panicked");
+    })
+    .join();
+    assert!(result.is_err());
+    // and one whose thread panics after a request
+    let mut panicking = open_instance(&name, &IndexOpenOptions::default()).unwrap();
+    let result = thread::spawn(move || {
+        ok(process_message(&mut panicking, add("p", 0)));
+        panic!("instance panicked");
+    })
+    .join();
+    assert!(result.is_err());
+
+    // the panicking instances are closed, the others go on
+    assert_eq!(open_count(&mut survivor), 2);
+    ok(process_message(&mut survivor, add("a", 0)));
+    ok(client.request(add("b", 0)).unwrap());
+    let query = Message::Query("find {}".to_string(), None);
+    assert_eq!(
+        query_ids(client.request(query).unwrap()),
+        vec!["a", "b", "p"]
+    );
+    client.close();
+    drop(survivor);
+    assert_eq!(ok(drop_index(&name, false)), JsonValue::True);
+}
+
+#[test]
+fn background_compaction_isnt_an_instance() {
+    let dir = test_dir("background_compaction_isnt_an_instance");
+    let name = dir.join("index").to_string_lossy().into_owned();
+    let options = IndexOpenOptions {
+        create_if_missing: true,
+        compact_deleted_threshold: Some(1),
+        ..Default::default()
+    };
+    let mut index = open_instance(&name, &options).unwrap();
+    ok(process_message(&mut index, add("a", 0)));
+    ok(process_message(
+        &mut index,
+        Message::Delete(vec!["a".to_string()]),
+    ));
+    // the compaction may still be running
+    assert_eq!(open_count(&mut index), 1);
+    drop(index);
+    // the index is closed once the compaction is done
+    let deadline = Instant::now() + Duration::from_secs(10);
+    loop {
+        match drop_index(&name, false) {
+            Message::ResponseOk(_) => break,
+            resp => {
+                assert_eq!(error(resp), "Index instances still open");
+                assert!(Instant::now() < deadline, "index stayed open");
+                thread::sleep(Duration::from_millis(10));
+            }
+        }
+    }
+    assert!(!Path::new(&name).exists());
+}
@@ -0,0 +1,53 @@
+extern crate noise_core;
+
+use std::env;
+use std::fs;
+use std::path::Path;
+
+use noise_core::{open_instance, IndexOpenOptions};
+
+// Its own test binary, as views of crashed processes are only removed by the
+// first view a process opens.
+#[test]
+fn views_are_removed() {
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("views");
+    let _ = fs::remove_dir_all(&dir);
+    let tmp = dir.join("tmp");
+    fs::create_dir_all(&tmp).unwrap();
+    env::set_var("TMPDIR", &tmp);
+    // above the largest possible process id, so it's not running
+    let stale = tmp.join("noise-read-only-99999999-0");
+    fs::create_dir(&stale).unwrap();
+    let other = tmp.join("unrelated");
+    fs::create_dir(&other).unwrap();
+
+    let name = dir.join("index").to_string_lossy().into_owned();
+    let options = IndexOpenOptions {
+        create_if_missing: true,
+        ..Default::default()
+    };
+    drop(open_instance(&name, &options).unwrap());
+    let options = IndexOpenOptions {
+        read_only: true,
+        ..Default::default()
+    };
+    let view = open_instance(&name, &options).unwrap();
+    // whether a process is running is only known where there is /proc
+    if Path::new("/proc/self").exists() {
+        assert!(!stale.exists(), "stale view is removed");
+    }
+    assert!(other.exists(), "other directories are kept");
+    let views = || {
+        fs::read_dir(&tmp)
+            .unwrap()
+            .filter(|entry| {
+                let name = entry.as_ref().unwrap().file_name();
+                name.to_string_lossy().starts_with("noise-read-only-")
+            })
+            .count()
+    };
+    assert_eq!(views(), 1, "the view is in the temporary directory");
+    drop(view);
+    assert_eq!(views(), 0, "closing removes the view");
+    let _ = fs::remove_dir_all(&dir);
+}
@@ -1,159 +1,157 @@
-#[macro_use]
+// The Node.js binding. It only converts between Javascript values and the
+// requests and responses of `noise_core`, which does the actual work.
+
 extern crate neon;
-extern crate noise_search;
-extern crate unix_socket;
+extern crate noise_core;
 #[macro_use]
 extern crate lazy_static;
 
 use std::collections::HashMap;
-use std::fs;
-use std::io::{BufRead, BufReader, Write};
-use std::mem::drop;
-use std::ops::Deref;
-use std::ops::DerefMut;
-use std::panic;
-use std::str;
-use std::sync::{Arc, Mutex};
-use std::thread;
-use std::vec::Vec;
-
-use unix_socket::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::Duration;
 
 use neon::{
-    context::{Context, FunctionContext},
+    context::{Context, FunctionContext, ModuleContext},
     handle::Handle,
     object::Object,
-    result::JsResult,
-    types::{JsArray, JsBoolean, JsNumber, JsString, JsUndefined, JsValue, Value},
+    result::{JsResult, NeonResult},
+    types::{
+        buffer::TypedArray, JsArray, JsBoolean, JsBuffer, JsNull, JsNumber, JsObject, JsString,
+        JsUndefined, JsValue, Value,
+    },
 };
 
-use noise_search::index::{Batch, Index, MvccRwLock, OpenOptions};
-use noise_search::json_value::JsonValue;
-
-enum Message {
-    OpenIndex(String, Option<OpenOptions>),
-    DropIndex(String),
-    Add(Vec<String>),
-    Delete(Vec<String>),
-    Query(String, Option<String>),
-    Close,
-    ResponseOk(JsonValue),
-    ResponseError(String),
-}
+use noise_core::wire;
+use noise_core::{AddOptions, DropOptions, IndexOpenOptions, JsonValue, Message};
 
-// this is a global that provides a messaging slot from node clients to send messages
-// to the server threads.
+// The last response each connection received, keyed by connection id, until
+// the client has read it.
 lazy_static! {
     static ref MESSAGE_MAP: Mutex<HashMap<u64, Option<Message>>> = Mutex::new(HashMap::new());
 }
 
-struct OpenedIndex {
-    index: Index,
-    open_count: usize,
+fn store_response(conn_id: u64, resp: Message) {
+    MESSAGE_MAP.lock().unwrap().insert(conn_id, Some(resp));
 }
 
-struct OpenedIndexCleanupGuard {
-    index: Arc<MvccRwLock<OpenedIndex>>,
+// Takes the response out of the slot of the connection, for the client to read.
+fn take_response(conn_id: u64) -> Result<Message, &'static str> {
+    match MESSAGE_MAP.lock().unwrap().get_mut(&conn_id) {
+        Some(slot) => slot.take().ok_or("missing response"),
+        None => Err("missing response"),
+    }
 }
 
-impl Drop for OpenedIndexCleanupGuard {
-    fn drop(&mut self) {
-        let opt_name = {
-            let mut rw_guard = match self.index.write() {
-                Ok(rw_guard) => rw_guard,
-                Err(poisoned) => poisoned.into_inner(),
-            };
-            rw_guard.open_count -= 1;
-            if rw_guard.open_count == 0 {
-                Some(rw_guard.index.get_name().to_string())
-            } else {
-                None
-            }
-        };
-        if opt_name.is_some() {
-            let mut guard = match OPEN_INSTANCES.lock() {
-                Ok(guard) => guard,
-                Err(poisoned) => poisoned.into_inner(),
-            };
-            guard.deref_mut().remove(&opt_name.unwrap());
-        }
+// Puts back what the client didn't read yet, unless the connection was
+// released meanwhile.
+fn put_back_response(conn_id: u64, resp: Message) {
+    if let Some(slot) = MESSAGE_MAP.lock().unwrap().get_mut(&conn_id) {
+        *slot = Some(resp);
     }
 }
 
-impl Deref for OpenedIndexCleanupGuard {
-    type Target = Arc<MvccRwLock<OpenedIndex>>;
-
-    fn deref(&self) -> &Arc<MvccRwLock<OpenedIndex>> {
-        &self.index
+// Drops the response the client won't read, like the rest of query results.
+fn clear_response(conn_id: u64) {
+    if let Some(slot) = MESSAGE_MAP.lock().unwrap().get_mut(&conn_id) {
+        *slot = None;
     }
 }
 
-impl DerefMut for OpenedIndexCleanupGuard {
-    fn deref_mut(&mut self) -> &mut Arc<MvccRwLock<OpenedIndex>> {
-        &mut self.index
-    }
+fn release_connection(conn_id: u64) {
+    MESSAGE_MAP.lock().unwrap().remove(&conn_id);
 }
 
-// This lock only allows one index to be updated at a time.
-lazy_static! {
-    static ref OPEN_INSTANCES: Mutex<HashMap<String, Arc<MvccRwLock<OpenedIndex>>>> =
-        Mutex::new(HashMap::new());
+fn js_start_listener(mut cx: FunctionContext) -> JsResult<JsUndefined> {
+    match noise_core::start_listener() {
+        Ok(()) => Ok(cx.undefined()),
+        Err(err) => cx.throw_error(err.to_string()),
+    }
 }
 
-fn js_start_listener(_cx: FunctionContext) -> JsResult<JsUndefined> {
-    let _ = fs::remove_file("echo.sock");
-    let listener = UnixListener::bind("echo.sock").unwrap();
-
-    thread::spawn(move || {
-        // accept connections and process them, spawning a new thread for each one
-        for stream in listener.incoming() {
-            match stream {
-                Ok(stream) => {
-                    /* connection succeeded */
-                    thread::spawn(move || handle_client_outer(stream));
-                }
-                Err(msg) => {
-                    /* connection failed */
-                    println!("Error connecting socket: {}", msg);
-                    break;
-                }
-            }
-        }
-    });
+fn js_start_daemon(mut cx: FunctionContext) -> JsResult<JsUndefined> {
+    let path = PathBuf::from(cx.argument::<JsString>(0)?.value(&mut cx));
+    match noise_core::start_daemon(path) {
+        Ok(()) => Ok(cx.undefined()),
+        Err(msg) => cx.throw_error(msg),
+    }
+}
 
-    Ok(JsUndefined::new())
+fn js_next_connection_id(mut cx: FunctionContext) -> JsResult<JsNumber> {
+    let connection_id = noise_core::next_connection_id();
+    Ok(cx.number(connection_id as f64))
 }
 
-fn js_send_message(mut cx: FunctionContext) -> JsResult<JsUndefined> {
-    let conn_id = cx.argument::<JsNumber>(0)?;
+// Returns the frame of a request, to be sent on the socket.
+fn js_encode_request(mut cx: FunctionContext) -> JsResult<JsBuffer> {
+    let request_id = cx.argument::<JsNumber>(0)?.value(&mut cx) as u32;
     let msg_type = cx.argument::<JsNumber>(1)?;
     let args = cx.argument::<JsArray>(2)?.to_vec(&mut cx)?;
 
-    let message = match msg_type.value() as u64 {
+    let message = match msg_type.value(&mut cx) as u64 {
         0 => {
             // open index
-            let name = args[0].downcast_or_throw::<JsString, _>(&mut cx)?.value();
-            let opt_create = if args[1].downcast_or_throw::<JsBoolean, _>(&mut cx)?.value() {
-                Some(OpenOptions::Create)
-            } else {
-                None
+            let name = args[0]
+                .downcast_or_throw::<JsString, _>(&mut cx)?
+                .value(&mut cx);
+            let options = args[1].downcast_or_throw::<JsObject, _>(&mut cx)?;
+            check_open_options(&mut cx, options)?;
+            let in_memory = name == ":memory:" || get_bool_option(&mut cx, options, "inMemory")?;
+            let options = IndexOpenOptions {
+                create_if_missing: get_bool_option(&mut cx, options, "createIfMissing")?,
+                error_if_exists: get_bool_option(&mut cx, options, "errorIfExists")?,
+                read_only: get_bool_option(&mut cx, options, "readOnly")?,
+                compact_deleted_threshold: get_number_option(
+                    &mut cx,
+                    options,
+                    "compactDeletedThreshold",
+                )?
+                .map(|count| count as usize),
+                in_memory,
+                temp: get_bool_option(&mut cx, options, "temp")?,
             };
-            Message::OpenIndex(name, opt_create)
+            Message::OpenIndex(name, options)
         }
         1 => {
             // drop index
-            Message::DropIndex(args[0].downcast_or_throw::<JsString, _>(&mut cx)?.value())
+            let options = match cx.argument_opt(3) {
+                Some(options) => {
+                    let options = options.downcast_or_throw::<JsObject, _>(&mut cx)?;
+                    DropOptions {
+                        dry_run: get_bool_option(&mut cx, options, "dryRun")?,
+                        force: get_bool_option(&mut cx, options, "force")?,
+                    }
+                }
+                None => DropOptions {
+                    dry_run: false,
+                    force: false,
+                },
+            };
+            Message::DropIndex(
+                args[0]
+                    .downcast_or_throw::<JsString, _>(&mut cx)?
+                    .value(&mut cx),
+                options,
+            )
         }
         2 => {
             // add documents
+            let percolate = match cx.argument_opt(3) {
+                Some(options) => {
+                    let options = options.downcast_or_throw::<JsObject, _>(&mut cx)?;
+                    get_bool_option(&mut cx, options, "percolate")?
+                }
+                None => false,
+            };
             Message::Add(
                 args.iter()
                     .map(|val| {
                         val.downcast_or_throw::<JsString, _>(&mut cx)
                             .unwrap()
-                            .value()
+                            .value(&mut cx)
                     })
                     .collect(),
+                AddOptions { percolate },
             )
         }
         3 => {
@@ -163,7 +161,7 @@ fn js_send_message(mut cx: FunctionContext) -> JsResult<JsUndefined> {
                     .map(|val| {
                         val.downcast_or_throw::<JsString, _>(&mut cx)
                             .unwrap()
-                            .value()
+                            .value(&mut cx)
                     })
                     .collect(),
             )
@@ -171,37 +169,296 @@ fn js_send_message(mut cx: FunctionContext) -> JsResult<JsUndefined> {
         4 => {
             // query
             let params = if args.len() == 2 {
-                Some(args[1].downcast_or_throw::<JsString, _>(&mut cx)?.value())
+                Some(
+                    args[1]
+                        .downcast_or_throw::<JsString, _>(&mut cx)?
+                        .value(&mut cx),
+                )
             } else {
                 None
             };
             Message::Query(
-                args[0].downcast_or_throw::<JsString, _>(&mut cx)?.value(),
+                args[0]
+                    .downcast_or_throw::<JsString, _>(&mut cx)?
+                    .value(&mut cx),
                 params,
             )
         }
         5 => Message::Close,
+        6 => {
+            // register a query for percolation
+            let params = if args.len() == 3 {
+                Some(
+                    args[2]
+                        .downcast_or_throw::<JsString, _>(&mut cx)?
+                        .value(&mut cx),
+                )
+            } else {
+                None
+            };
+            Message::RegisterQuery(
+                args[0]
+                    .downcast_or_throw::<JsString, _>(&mut cx)?
+                    .value(&mut cx),
+                args[1]
+                    .downcast_or_throw::<JsString, _>(&mut cx)?
+                    .value(&mut cx),
+                params,
+            )
+        }
+        7 => {
+            // unregister a percolation query
+            Message::UnregisterQuery(
+                args[0]
+                    .downcast_or_throw::<JsString, _>(&mut cx)?
+                    .value(&mut cx),
+            )
+        }
+        8 => Message::Info,
+        9 => {
+            // schema, optionally only sampling some documents
+            let sample_size = if args.len() == 1 {
+                Some(
+                    args[0]
+                        .downcast_or_throw::<JsNumber, _>(&mut cx)?
+                        .value(&mut cx) as usize,
+                )
+            } else {
+                None
+            };
+            Message::Schema(sample_size)
+        }
+        10 => {
+            // back up the index into a directory
+            let keep = if args.len() == 2 {
+                Some(
+                    args[1]
+                        .downcast_or_throw::<JsNumber, _>(&mut cx)?
+                        .value(&mut cx) as u32,
+                )
+            } else {
+                None
+            };
+            Message::Backup(
+                args[0]
+                    .downcast_or_throw::<JsString, _>(&mut cx)?
+                    .value(&mut cx),
+                keep,
+            )
+        }
+        11 => {
+            // restore an index from a backup
+            let backup_id = if args.len() == 3 {
+                Some(
+                    args[2]
+                        .downcast_or_throw::<JsNumber, _>(&mut cx)?
+                        .value(&mut cx) as u32,
+                )
+            } else {
+                None
+            };
+            Message::Restore(
+                args[0]
+                    .downcast_or_throw::<JsString, _>(&mut cx)?
+                    .value(&mut cx),
+                args[1]
+                    .downcast_or_throw::<JsString, _>(&mut cx)?
+                    .value(&mut cx),
+                backup_id,
+            )
+        }
+        12 => {
+            // list the backups in a directory
+            Message::ListBackups(
+                args[0]
+                    .downcast_or_throw::<JsString, _>(&mut cx)?
+                    .value(&mut cx),
+            )
+        }
+        13 => Message::MemoryUsage,
+        14 => {
+            // compact the whole index. There are no options, as only the keys
+            // mapping ids to documents are ordered by id, so a range of ids
+            // can't be compacted on its own.
+            if let Some(options) = cx.argument_opt(3) {
+                let options = options.downcast_or_throw::<JsObject, _>(&mut cx)?;
+                if options.get_own_property_names(&mut cx)?.len(&mut cx) > 0 {
+                    return cx.throw_error("compact takes no options, it compacts the whole index");
+                }
+            }
+            Message::Compact
+        }
+        15 => {
+            // list the indexes in a directory
+            Message::ListIndexes(
+                args[0]
+                    .downcast_or_throw::<JsString, _>(&mut cx)?
+                    .value(&mut cx),
+            )
+        }
+        16 => {
+            // copy an index
+            Message::CopyIndex(
+                args[0]
+                    .downcast_or_throw::<JsString, _>(&mut cx)?
+                    .value(&mut cx),
+                args[1]
+                    .downcast_or_throw::<JsString, _>(&mut cx)?
+                    .value(&mut cx),
+            )
+        }
+        17 => {
+            // rename an index
+            Message::RenameIndex(
+                args[0]
+                    .downcast_or_throw::<JsString, _>(&mut cx)?
+                    .value(&mut cx),
+                args[1]
+                    .downcast_or_throw::<JsString, _>(&mut cx)?
+                    .value(&mut cx),
+            )
+        }
         _ => {
             return cx.throw_error("unknown message type");
         }
     };
 
-    MESSAGE_MAP
-        .lock()
-        .unwrap()
-        .deref_mut()
-        .insert(conn_id.value() as u64, Some(message));
+    let frame = match wire::Frame::new(request_id, &message) {
+        Ok(frame) => frame,
+        Err(msg) => return cx.throw_error(msg),
+    };
+    Ok(JsBuffer::external(&mut cx, frame.to_bytes()))
+}
+
+// Puts the response in a frame received on the socket into the message slot
+// of the connection.
+fn js_receive_response(mut cx: FunctionContext) -> JsResult<JsUndefined> {
+    let conn_id = cx.argument::<JsNumber>(0)?.value(&mut cx) as u64;
+    let request_id = cx.argument::<JsNumber>(1)?.value(&mut cx) as u32;
+    let bytes = cx.argument::<JsBuffer>(2)?;
+    let frame = match wire::read_frame(&mut bytes.as_slice(&cx)) {
+        Ok(Some(frame)) => frame,
+        Ok(None) => return cx.throw_error("missing response"),
+        Err(msg) => return cx.throw_error(msg.to_string()),
+    };
+    if frame.request_id != request_id {
+        return cx.throw_error(format!(
+            "response to request {}, expected {}",
+            frame.request_id, request_id
+        ));
+    }
+    let resp = match frame.message() {
+        Ok(resp @ Message::ResponseOk(_)) | Ok(resp @ Message::ResponseError(_)) => resp,
+        Ok(_msg) => return cx.throw_error("response expected"),
+        Err(msg) => return cx.throw_error(msg),
+    };
+    store_response(conn_id, resp);
+    Ok(cx.undefined())
+}
+
+// Removes the message slot of a connection that ended.
+fn js_release_connection(mut cx: FunctionContext) -> JsResult<JsUndefined> {
+    let conn_id = cx.argument::<JsNumber>(0)?.value(&mut cx) as u64;
+    release_connection(conn_id);
+    Ok(cx.undefined())
+}
 
+fn js_set_base_dir(mut cx: FunctionContext) -> JsResult<JsUndefined> {
+    let base_dir = match cx.argument_opt(0) {
+        Some(dir) if dir.is_a::<JsString, _>(&mut cx) => Some(PathBuf::from(
+            dir.downcast::<JsString, _>(&mut cx).unwrap().value(&mut cx),
+        )),
+        Some(dir) if !dir.is_a::<JsUndefined, _>(&mut cx) && !dir.is_a::<JsNull, _>(&mut cx) => {
+            return cx.throw_type_error("base directory must be a string");
+        }
+        _ => None,
+    };
+    noise_core::set_base_dir(base_dir);
     Ok(cx.undefined())
 }
 
+fn js_shutdown(mut cx: FunctionContext) -> JsResult<JsBoolean> {
+    let timeout = cx.argument::<JsNumber>(0)?.value(&mut cx).max(0.0);
+    let closed = noise_core::shutdown(Duration::from_millis(timeout as u64));
+    Ok(cx.boolean(closed))
+}
+
+// Returns the boolean option `key`, which is false if it isn't set.
+fn get_bool_option<'a>(
+    cx: &mut FunctionContext<'a>,
+    options: Handle<'a, JsObject>,
+    key: &str,
+) -> NeonResult<bool> {
+    let value = options.get::<JsValue, _, _>(cx, key)?;
+    if value.is_a::<JsUndefined, _>(cx) {
+        Ok(false)
+    } else {
+        Ok(value.downcast_or_throw::<JsBoolean, _>(cx)?.value(cx))
+    }
+}
+
+// The options of open. `daemon` is handled in JavaScript.
+const OPEN_OPTIONS: [&str; 7] = [
+    "createIfMissing",
+    "errorIfExists",
+    "readOnly",
+    "inMemory",
+    "compactDeletedThreshold",
+    "temp",
+    "daemon",
+];
+
+// RocksDB tuning options noise_search doesn't let us pass on, as it opens
+// RocksDB with fixed options.
+const UNSUPPORTED_OPEN_OPTIONS: [&str; 4] = [
+    "blockCacheSize",
+    "writeBufferSize",
+    "compression",
+    "maxOpenFiles",
+];
+
+// Throws if any of the options of open is unknown or unsupported, instead of
+// silently ignoring it.
+fn check_open_options<'a>(
+    cx: &mut FunctionContext<'a>,
+    options: Handle<'a, JsObject>,
+) -> NeonResult<()> {
+    let keys = options.get_own_property_names(cx)?.to_vec(cx)?;
+    for key in keys {
+        let key = key.to_string(cx)?.value(cx);
+        if UNSUPPORTED_OPEN_OPTIONS.contains(&key.as_str()) {
+            return cx.throw_error(format!(
+                "Option '{}' isn't supported by this version of noise",
+                key
+            ));
+        } else if !OPEN_OPTIONS.contains(&key.as_str()) {
+            return cx.throw_error(format!("Unknown option '{}'", key));
+        }
+    }
+    Ok(())
+}
+
+// Returns the number option `key` if it is set.
+fn get_number_option<'a>(
+    cx: &mut FunctionContext<'a>,
+    options: Handle<'a, JsObject>,
+    key: &str,
+) -> NeonResult<Option<f64>> {
+    let value = options.get::<JsValue, _, _>(cx, key)?;
+    if value.is_a::<JsUndefined, _>(cx) {
+        Ok(None)
+    } else {
+        Ok(Some(value.downcast_or_throw::<JsNumber, _>(cx)?.value(cx)))
+    }
+}
+
 fn js_get_response(mut cx: FunctionContext) -> JsResult<JsValue> {
-    let conn_id = cx.argument::<JsNumber>(0)?.value() as u64;
-    let res = match MESSAGE_MAP.lock().unwrap().deref_mut().get_mut(&conn_id) {
-        Some(ref mut res) => res.take(),
-        None => return cx.throw_error("missing response"),
+    let conn_id = cx.argument::<JsNumber>(0)?.value(&mut cx) as u64;
+    let res = match take_response(conn_id) {
+        Ok(res) => res,
+        Err(msg) => return cx.throw_error(msg),
     };
-    match res.unwrap() {
+    match res {
         Message::ResponseOk(json) => Ok(convert_json(&mut cx, json)),
         Message::ResponseError(msg) => cx.throw_error(&msg),
         _ => panic!("Non-response message"),
@@ -209,21 +466,15 @@ fn js_get_response(mut cx: FunctionContext) -> JsResult<JsValue> {
 }
 
 fn js_get_error(mut cx: FunctionContext) -> JsResult<JsUndefined> {
-    let conn_id = cx.argument::<JsNumber>(0)?.value() as u64;
-    let res = match MESSAGE_MAP.lock().unwrap().deref_mut().get_mut(&conn_id) {
-        Some(ref mut res) => res.take(),
-        None => return cx.throw_error("missing response"),
+    let conn_id = cx.argument::<JsNumber>(0)?.value(&mut cx) as u64;
+    let res = match take_response(conn_id) {
+        Ok(res) => res,
+        Err(msg) => return cx.throw_error(msg),
     };
-    match res.unwrap() {
+    match res {
         Message::ResponseOk(json) => {
-            //put back
-            *MESSAGE_MAP
-                .lock()
-                .unwrap()
-                .deref_mut()
-                .get_mut(&conn_id)
-                .unwrap() = Some(Message::ResponseOk(json));
-            Ok(JsUndefined::new())
+            put_back_response(conn_id, Message::ResponseOk(json));
+            Ok(cx.undefined())
         }
         Message::ResponseError(msg) => cx.throw_error(&msg),
         _ => panic!("Non-response message"),
@@ -231,12 +482,12 @@ fn js_get_error(mut cx: FunctionContext) -> JsResult<JsUndefined> {
 }
 
 fn js_query_next(mut cx: FunctionContext) -> JsResult<JsValue> {
-    let conn_id = cx.argument::<JsNumber>(0)?.value() as u64;
-    let res = match MESSAGE_MAP.lock().unwrap().deref_mut().get_mut(&conn_id) {
-        Some(ref mut res) => res.take(),
-        None => return cx.throw_error("missing response"),
+    let conn_id = cx.argument::<JsNumber>(0)?.value(&mut cx) as u64;
+    let res = match take_response(conn_id) {
+        Ok(res) => res,
+        Err(msg) => return cx.throw_error(msg),
     };
-    match res.unwrap() {
+    match res {
         Message::ResponseOk(JsonValue::Array(mut vec)) => {
             if let Some(ret) = vec.pop() {
                 let next = convert_json(&mut cx, ret);
@@ -245,12 +496,7 @@ fn js_query_next(mut cx: FunctionContext) -> JsResult<JsValue> {
                 assert!(obj.set(&mut cx, "value", next).is_ok());
                 assert!(obj.set(&mut cx, "done", done).is_ok());
                 // put the remaining vec back
-                *MESSAGE_MAP
-                    .lock()
-                    .unwrap()
-                    .deref_mut()
-                    .get_mut(&conn_id)
-                    .unwrap() = Some(Message::ResponseOk(JsonValue::Array(vec)));
+                put_back_response(conn_id, Message::ResponseOk(JsonValue::Array(vec)));
 
                 Ok(obj.as_value(&mut cx))
             } else {
@@ -267,15 +513,9 @@ fn js_query_next(mut cx: FunctionContext) -> JsResult<JsValue> {
 }
 
 fn js_query_unref(mut cx: FunctionContext) -> JsResult<JsUndefined> {
-    let conn_id = cx.argument::<JsNumber>(0)?.value() as u64;
-    match MESSAGE_MAP.lock().unwrap().deref_mut().get_mut(&conn_id) {
-        Some(ref mut res) => {
-            let _ = res.take();
-            ()
-        }
-        None => (),
-    }
-    Ok(JsUndefined::new())
+    let conn_id = cx.argument::<JsNumber>(0)?.value(&mut cx) as u64;
+    clear_response(conn_id);
+    Ok(cx.undefined())
 }
 
 fn convert_json<'a>(cx: &mut FunctionContext<'a>, json_in: JsonValue) -> Handle<'a, JsValue> {
@@ -304,279 +544,71 @@ fn convert_json<'a>(cx: &mut FunctionContext<'a>, json_in: JsonValue) -> Handle<
     }
 }
 
-fn handle_client_outer(stream: UnixStream) {
-    let mut reader = BufReader::new(stream);
-    let mut buf = Vec::new();
+#[neon::main]
+fn main(mut cx: ModuleContext) -> NeonResult<()> {
+    cx.export_function("startListener", js_start_listener)?;
+    cx.export_function("nextConnectionId", js_next_connection_id)?;
+    cx.export_function("getResponse", js_get_response)?;
+    cx.export_function("encodeRequest", js_encode_request)?;
+    cx.export_function("receiveResponse", js_receive_response)?;
+    cx.export_function("releaseConnection", js_release_connection)?;
+    cx.export_function("queryNext", js_query_next)?;
+    cx.export_function("getError", js_get_error)?;
+    cx.export_function("queryUnref", js_query_unref)?;
+    cx.export_function("setBaseDir", js_set_base_dir)?;
+    cx.export_function("shutdown", js_shutdown)?;
+    cx.export_function("startDaemon", js_start_daemon)?;
+    Ok(())
+}
 
-    // first get our connection_id through the pipe.
-    let connection_id = match reader.read_until(b';', &mut buf) {
-        Ok(_size) => {
-            buf.pop(); // remove trailing ';'
-            str::from_utf8(&buf).unwrap().parse::<u64>().unwrap()
-        }
-        Err(msg) => {
-            println!("Error reading socket: {}", msg);
-            return;
-        }
-    };
+#[cfg(test)]
+mod tests {
+    use std::thread;
 
-    match reader.read_until(b'0', &mut buf) {
-        Ok(0) => return,
-        Ok(1) => {
-            // first get the message
-            let msg = {
-                MESSAGE_MAP
-                    .lock()
-                    .unwrap()
-                    .deref_mut()
-                    .get_mut(&connection_id)
-                    .unwrap()
-                    .take()
-                    .unwrap()
-            };
-            match msg {
-                Message::OpenIndex(name, options) => {
-                    let mut index: Option<Arc<MvccRwLock<OpenedIndex>>> = None;
-                    let resp = {
-                        let mut guard = OPEN_INSTANCES.lock().unwrap();
-                        let map = guard.deref_mut();
-                        let needs_opening = match map.get_mut(&name) {
-                            None => true,
-                            Some(opened_index) => {
-                                index = Some(opened_index.clone());
-                                opened_index.write().unwrap().open_count += 1;
-                                false
-                            }
-                        };
-                        if needs_opening {
-                            match Index::open(&name, options) {
-                                Ok(new_index) => {
-                                    let new_index = Arc::new(MvccRwLock::new(OpenedIndex {
-                                        index: new_index,
-                                        open_count: 1,
-                                    }));
-                                    map.insert(name.clone(), new_index.clone());
-                                    index = Some(new_index);
-                                    Message::ResponseOk(JsonValue::True)
-                                }
-                                Err(msg) => Message::ResponseError(msg.to_string()),
-                            }
-                        } else {
-                            Message::ResponseOk(JsonValue::True)
-                        }
-                    };
-                    // put the response in the queue
-                    {
-                        *MESSAGE_MAP
-                            .lock()
-                            .unwrap()
-                            .deref_mut()
-                            .get_mut(&connection_id)
-                            .unwrap() = Some(resp);
-                    }
+    use super::*;
 
-                    {
-                        // notify the client the response is ready
-                        let writer = reader.get_mut();
-                        writer.write_all(&[b'1']).unwrap();
-                        writer.flush().unwrap();
-                    }
-                    if index.is_some() {
-                        let index_guard = OpenedIndexCleanupGuard {
-                            index: index.unwrap(),
-                        };
-                        // now start servicing instance requests
-                        let result = panic::catch_unwind(|| {
-                            handle_client(index_guard, reader, connection_id);
-                        });
-                        if result.is_err() {
-                            println!("panic happend!")
-                        }
-                    }
-                    {
-                        // clean up message slot
-                        MESSAGE_MAP
-                            .lock()
-                            .unwrap()
-                            .deref_mut()
-                            .remove(&connection_id);
-                    }
-                }
-                Message::DropIndex(name) => {
-                    let mut guard = OPEN_INSTANCES.lock().unwrap();
-                    let map = guard.deref_mut();
-                    let resp = if map.contains_key(&name) {
-                        Message::ResponseError("Index instances still open".to_string())
-                    } else {
-                        match Index::drop(&name) {
-                            Ok(()) => Message::ResponseOk(JsonValue::True),
-                            Err(msg) => Message::ResponseError(msg.to_string()),
-                        }
-                    };
-                    {
-                        // put the response in the queue
-                        *MESSAGE_MAP
-                            .lock()
-                            .unwrap()
-                            .deref_mut()
-                            .get_mut(&connection_id)
-                            .unwrap() = Some(resp);
-                    }
-                    {
-                        // notify the client the response is ready
-                        let writer = reader.get_mut();
-                        writer.write_all(&[b'1']).unwrap();
-                        writer.flush().unwrap();
-                    }
-
-                    // when the socket closes we'll know we can clean up the message slot.
-                    let _ = reader.read_until(b'0', &mut buf);
-                    {
-                        // clean up message slot
-                        MESSAGE_MAP
-                            .lock()
-                            .unwrap()
-                            .deref_mut()
-                            .remove(&connection_id);
-                    }
-                }
-                _ => panic!("unexpected message"),
-            }
-        }
-        Ok(_size) => panic!("WTF, more than one byte read!"),
-        Err(msg) => println!("Error reading socket: {}", msg),
+    fn slot_count(conn_ids: &[u64]) -> usize {
+        let map = MESSAGE_MAP.lock().unwrap();
+        conn_ids.iter().filter(|id| map.contains_key(id)).count()
     }
-}
-
-fn handle_client(
-    mut index: OpenedIndexCleanupGuard,
-    mut reader: BufReader<UnixStream>,
-    connection_id: u64,
-) {
-    let mut buf = Vec::new();
-    loop {
-        // from now on the stream only sends a single byte on value 0
-        // to indicate there is a message waiting,
-        match reader.read_until(b'0', &mut buf) {
-            Ok(0) => break,
-            Ok(1) => {
-                // first get the message
-                let msg = {
-                    MESSAGE_MAP
-                        .lock()
-                        .unwrap()
-                        .deref_mut()
-                        .get_mut(&connection_id)
-                        .unwrap()
-                        .take()
-                        .unwrap()
-                };
-
-                if let Message::Close = msg {
-                    drop(index); // make sure index instance is closed first
-                    return; // now we end the loop. The client will notice the socket close.
-                }
-                // process the message
-                let response = process_message(&mut index, msg);
-
-                // put the response in the queue
-                {
-                    *MESSAGE_MAP
-                        .lock()
-                        .unwrap()
-                        .deref_mut()
-                        .get_mut(&connection_id)
-                        .unwrap() = Some(response);
-                }
 
-                // notify the client the response is ready
-                let writer = reader.get_mut();
-                writer.write_all(&[b'1']).unwrap();
-                writer.flush().unwrap();
-            }
-            Ok(_size) => panic!("WTF, more than one byte read!"),
-            Err(msg) => println!("Error reading socket: {}", msg),
-        }
+    #[test]
+    fn responses_are_read_once() {
+        let conn_id = noise_core::next_connection_id();
+        store_response(conn_id, Message::ResponseOk(JsonValue::True));
+        assert!(take_response(conn_id).is_ok());
+        assert_eq!(take_response(conn_id).err(), Some("missing response"));
+        put_back_response(conn_id, Message::ResponseOk(JsonValue::False));
+        clear_response(conn_id);
+        assert!(take_response(conn_id).is_err());
+        release_connection(conn_id);
+        assert_eq!(slot_count(&[conn_id]), 0);
     }
-}
 
-fn process_message(index: &mut OpenedIndexCleanupGuard, message: Message) -> Message {
-    match message {
-        Message::Add(vec) => {
-            let mut results = Vec::with_capacity(vec.len());
-            let mut batch = Batch::new();
-            let ref mut index = index.write().unwrap().index;
-            for doc_str in vec {
-                match index.add(&doc_str, &mut batch) {
-                    Ok(id) => results.push(JsonValue::String(id)),
-                    Err(reason) => {
-                        let err_str = JsonValue::String(reason.to_string());
-                        let err_obj = vec![("error".to_string(), err_str)];
-                        results.push(JsonValue::Object(err_obj))
+    #[test]
+    fn released_connections_leave_no_slots() {
+        let threads: Vec<_> = (0..8)
+            .map(|_| {
+                thread::spawn(|| {
+                    let conn_ids: Vec<u64> =
+                        (0..100).map(|_| noise_core::next_connection_id()).collect();
+                    for &conn_id in &conn_ids {
+                        store_response(conn_id, Message::ResponseOk(JsonValue::Null));
                     }
-                }
-            }
-            match index.flush(batch) {
-                Ok(()) => Message::ResponseOk(JsonValue::Array(results)),
-                Err(reason) => Message::ResponseError(reason.to_string()),
-            }
-        }
-        Message::Delete(vec) => {
-            let ref mut index = index.write().unwrap().index;
-            let mut batch = Batch::new();
-            let mut results = Vec::with_capacity(vec.len());
-            for doc_str in vec {
-                match index.delete(&doc_str, &mut batch) {
-                    Ok(true) => results.push(JsonValue::True),
-                    Ok(false) => results.push(JsonValue::False),
-                    Err(reason) => {
-                        let err_str = JsonValue::String(reason.to_string());
-                        let err_obj = vec![("error".to_string(), err_str)];
-                        results.push(JsonValue::Object(err_obj))
+                    assert_eq!(slot_count(&conn_ids), conn_ids.len());
+                    for &conn_id in &conn_ids {
+                        release_connection(conn_id);
+                        // a response put back after the release doesn't bring
+                        // the slot back
+                        put_back_response(conn_id, Message::ResponseOk(JsonValue::Null));
                     }
-                }
-            }
-            match index.flush(batch) {
-                Ok(()) => Message::ResponseOk(JsonValue::Array(results)),
-                Err(reason) => Message::ResponseError(reason.to_string()),
-            }
-        }
-        Message::Query(query, params) => {
-            let ref index = index.read().index;
-            let msg = match index.query(&query, params) {
-                Ok(results) => {
-                    let mut vec: Vec<JsonValue> = results.collect();
-                    vec.reverse(); // reverse so the client iterator can pop vals off end.
-                    Message::ResponseOk(JsonValue::Array(vec))
-                }
-                Err(reason) => Message::ResponseError(reason.to_string()),
-            };
-            msg
-        }
-        Message::Close => {
-            panic!("Can't get close message here!");
-        }
-        Message::ResponseOk(_json) => {
-            panic!("Got ResponseOk on wrong side!");
-        }
-        Message::ResponseError(_string) => {
-            panic!("Got ResponseError on wrong side!");
-        }
-        Message::OpenIndex(_, _) => {
-            panic!("Can't get OpenIndex message here!");
-        }
-        Message::DropIndex(_) => {
-            panic!("Can't get DropIndex message here!");
+                    conn_ids
+                })
+            })
+            .collect();
+        for thread in threads {
+            let conn_ids = thread.join().unwrap();
+            assert_eq!(slot_count(&conn_ids), 0);
         }
     }
 }
-
-register_module!(mut cx, {
-    cx.export_function("startListener", js_start_listener)?;
-    cx.export_function("getResponse", js_get_response)?;
-    cx.export_function("sendMessage", js_send_message)?;
-    cx.export_function("queryNext", js_query_next)?;
-    cx.export_function("getError", js_get_error)?;
-    cx.export_function("queryUnref", js_query_unref)?;
-    Ok(())
-});
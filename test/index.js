@@ -114,8 +114,7 @@ test('test multi concurrent add', function(t, done) {
             assert.ok(false, "add should have failed");
         }).catch(err => {
             assert.ok(true, "dropped index didn't reopen");
-            // for some damn reason attempting to open an non-existant
-            // index creates the dir and a LOG and LOCK file. clean it up here.
+            assert.ok(!require('fs').existsSync("tmp/multiadd"), "failed open left no files");
             noise.drop("tmp/multiadd").then(() => {
                 done();
             });
@@ -150,8 +149,7 @@ test('test multi instances opened', function(t, done) {
             assert.ok(false, "shouldn't happen");
         }).catch(err => {
             assert.ok(true, "dropped index didn't reopen");
-            // for some damn reason attempting to open an non-existant
-            // index creates the dir and a LOG and LOCK file. clean it up here.
+            assert.ok(!require('fs').existsSync("tmp/multiinst"), "failed open left no files");
             noise.drop("tmp/multiinst").then(() => {
                 done();
             });
@@ -410,3 +408,1065 @@ test('test geojson', function(t, done) {
         assert.ok(false, "should be no error");
     });
 });
+
+test('test percolate', function(t, done) {
+    var index = noise.open("tmp/percolate", true);
+    index.registerQuery("bars", 'find {foo: == @foo}', {foo: "bar"}).then(resp => {
+        assert.equal(resp, true, "query registered");
+        return index.registerQuery("all", 'find {foo: == "bar" || foo: == "baz"}');
+    }).then(() => {
+        // only the filter of a query matters
+        return index.registerQuery("quxes", 'find {foo: == "qux"} return .foo limit 1');
+    }).then(() => {
+        return index.add([{_id:"a",foo:"bar"}, {_id:"b", foo:"baz"}, {_id:"c", foo:"qux"},
+                          {_id:"a", foo:"qux"}], {percolate: true});
+    }).then(resp => {
+        assert.deepEqual(resp, [{_id: "a", matches: ["all", "bars"]},
+                                {_id: "b", matches: ["all"]},
+                                {_id: "c", matches: ["quxes"]},
+                                {_id: "a", matches: ["quxes"]}], "matches found");
+        return index.unregisterQuery("all");
+    }).then(resp => {
+        assert.equal(resp, true, "query unregistered");
+        return index.add({_id:"d",foo:"bar"}, {percolate: true});
+    }).then(resp => {
+        assert.deepEqual(resp, [{_id: "d", matches: ["bars"]}], "only bars matches");
+        return index.registerQuery("bad", 'find {foo: == "bar"');
+    }).then(() => {
+        assert.ok(false, "bad query shouldn't register");
+    }).catch(error => {
+        assert.ok(true, "expected: " + error);
+        index.close().then(() => {
+            return noise.drop("tmp/percolate");
+        }).then(() => {
+            done();
+        });
+    });
+});
+
+test('test info', function(t, done) {
+    var index = noise.open("tmp/info", true);
+    var index2;
+    index.info().then(info => {
+        assert.equal(info.name, "tmp/info", "name");
+        assert.equal(info.docCount, 0, "empty index");
+        assert.equal(info.lastFlush, null, "nothing written yet");
+        return index.add([{_id:"a",foo:"bar"}, {_id:"b", baz:[{biz: 1}]}]);
+    }).then(() => {
+        // open the second instance once the index exists
+        index2 = noise.open("tmp/info", false);
+        return index2.query('find {foo: == "bar"}');
+    }).then(iter => {
+        assert.deepEqual([...iter], ["a"], "second instance sees the docs");
+        return index.info();
+    }).then(info => {
+        assert.equal(info.docCount, 2, "two docs");
+        // _id, foo, baz, baz[] and baz[].biz
+        assert.equal(info.fieldPaths, 5, "field paths");
+        assert.equal(info.openCount, 2, "two instances open");
+        assert.ok(info.diskSize > 0, "index has a size");
+        assert.ok(info.lastFlush <= Date.now(), "flushed");
+        assert.equal(typeof info.rocksdb.sstBytes, "number", "rocksdb stats");
+        return index2.close();
+    }).then(() => {
+        return index.close();
+    }).then(() => {
+        return noise.drop("tmp/info");
+    }).then(() => {
+        done();
+    }).catch(error => {
+        console.log(error);
+        assert.ok(false, "should be no error");
+    });
+});
+
+test('test schema', function(t, done) {
+    var index = noise.open("tmp/schema", true);
+    index.add([{_id:"a", foo:"bar"}, {_id:"b", foo:1, baz:[true, null, {biz:"a"}]}]).then(() => {
+        return index.schema();
+    }).then(schema => {
+        assert.deepEqual(schema, {
+            docCount: 2,
+            paths: {
+                "_id": {docs: 2, types: {string: 2}},
+                "baz": {docs: 1, types: {array: 1}},
+                "baz[]": {docs: 1, types: {bool: 1, null: 1, object: 1}},
+                "baz[].biz": {docs: 1, types: {string: 1}},
+                "foo": {docs: 2, types: {number: 1, string: 1}}
+            }
+        }, "schema is correct");
+        return index.schema({sampleSize: 1});
+    }).then(schema => {
+        assert.equal(schema.docCount, 1, "only one doc sampled");
+        return index.close();
+    }).then(() => {
+        return noise.drop("tmp/schema");
+    }).then(() => {
+        done();
+    }).catch(error => {
+        console.log(error);
+        assert.ok(false, "should be no error");
+    });
+});
+
+test('test backup and restore', function(t, done) {
+    var index = noise.open("tmp/backup", true);
+    index.add([{_id:"a",foo:"bar"}, {_id:"b", foo:"baz"}]).then(() => {
+        return index.backup("tmp/backup_dir");
+    }).then(info => {
+        assert.equal(typeof info.backupId, "number", "backup created");
+        assert.ok(info.size > 0, "backup has a size");
+        return noise.restore("tmp/backup_dir", "tmp/backup");
+    }).then(() => {
+        assert.ok(false, "restore over an open index should fail");
+    }).catch(error => {
+        assert.ok(true, "expected: " + error);
+        return index.close().then(() => {
+            return noise.restore("tmp/backup_dir", "tmp/restored");
+        }).then(() => {
+            var restored = noise.open("tmp/restored");
+            return restored.query('find {foo: == "bar"}').then(iter => {
+                assert.deepEqual(Array.from(iter), ["a"], "restored index has docs");
+                return restored.close();
+            });
+        }).then(() => {
+            return noise.drop("tmp/restored");
+        }).then(() => {
+            return noise.drop("tmp/backup");
+        }).then(() => {
+            done();
+        });
+    }).catch(error => {
+        console.log(error);
+        assert.ok(false, "should be no error");
+    });
+});
+
+test('test incremental backups', function(t, done) {
+    var fs = require('fs');
+    var index = noise.open("tmp/incbackup", true);
+    var first;
+    var latest;
+    index.add({_id:"a",foo:"bar"}).then(() => {
+        return index.backup("tmp/incbackup_dir");
+    }).then(info => {
+        first = info.backupId;
+        return index.add({_id:"b",foo:"bar"});
+    }).then(() => {
+        return index.backup("tmp/incbackup_dir");
+    }).then(() => {
+        return noise.backups("tmp/incbackup_dir");
+    }).then(backups => {
+        assert.equal(backups.length, 2, "two backups");
+        assert.equal(backups[0].backupId, first, "oldest first");
+        return index.close();
+    }).then(() => {
+        return noise.restore("tmp/incbackup_dir", "tmp/increstored", {backupId: first});
+    }).then(() => {
+        var restored = noise.open("tmp/increstored");
+        return restored.query('find {foo: == "bar"}').then(iter => {
+            assert.deepEqual(Array.from(iter), ["a"], "first backup restored");
+            return restored.close();
+        });
+    }).then(() => {
+        return noise.restore("tmp/incbackup_dir", "tmp/increstored");
+    }).then(() => {
+        var restored = noise.open("tmp/increstored");
+        return restored.query('find {foo: == "bar"}').then(iter => {
+            assert.deepEqual(Array.from(iter), ["a", "b"], "latest backup restored");
+            return restored.close();
+        });
+    }).then(() => {
+        // only indexes are replaced by a restore
+        fs.mkdirSync("tmp/increstore_other", {recursive: true});
+        fs.writeFileSync("tmp/increstore_other/important.txt", "keep me");
+        return noise.restore("tmp/incbackup_dir", "tmp/increstore_other").then(() => {
+            assert.ok(false, "restore over a directory that isn't an index should fail");
+        }, error => {
+            assert.ok(/isn't a noise index/.test(error), "expected: " + error);
+            assert.ok(fs.existsSync("tmp/increstore_other/important.txt"), "file kept");
+        });
+    }).then(() => {
+        index = noise.open("tmp/incbackup");
+        return index.backup("tmp/incbackup_dir", {keep: 1});
+    }).then(info => {
+        latest = info.backupId;
+        return index.close();
+    }).then(() => {
+        return noise.backups("tmp/incbackup_dir");
+    }).then(backups => {
+        assert.deepEqual(backups.map(backup => backup.backupId), [latest],
+                         "only the newest backup kept");
+        return noise.drop("tmp/increstored");
+    }).then(() => {
+        return noise.drop("tmp/incbackup");
+    }).then(() => {
+        done();
+    }).catch(error => {
+        console.log(error);
+        assert.ok(false, "should be no error");
+    });
+});
+
+test('test read only', function(t, done) {
+    var index = noise.open("tmp/readonly", {createIfMissing: true});
+    var readOnly;
+    index.add({_id:"a",foo:"bar"}).then(() => {
+        readOnly = noise.open("tmp/readonly", {readOnly: true});
+        return readOnly.query('find {foo: == "bar"}');
+    }).then(iter => {
+        assert.deepEqual(Array.from(iter), ["a"], "read only instance can query");
+        return readOnly.add({_id:"b",foo:"bar"});
+    }).then(() => {
+        assert.ok(false, "add should have failed");
+    }).catch(error => {
+        assert.ok(/read-only/.test(error), "expected: " + error);
+        return readOnly.delete("a").then(() => {
+            assert.ok(false, "delete should have failed");
+        }).catch(error => {
+            assert.ok(/read-only/.test(error), "expected: " + error);
+            return readOnly.registerQuery("bars", 'find {foo: == "bar"}');
+        }).then(() => {
+            assert.ok(false, "registering a query should have failed");
+        }, error => {
+            assert.ok(/read-only/.test(error), "expected: " + error);
+            return readOnly.compact();
+        }).then(() => {
+            assert.ok(false, "compact should have failed");
+        }, error => {
+            assert.ok(/read-only/.test(error), "expected: " + error);
+            return readOnly.backup("tmp/readonly_backup");
+        }).then(() => {
+            assert.ok(false, "backup should have failed");
+        }, error => {
+            assert.ok(/read-only/.test(error), "expected: " + error);
+            return readOnly.close();
+        }).then(() => {
+            return index.close();
+        }).then(() => {
+            return noise.drop("tmp/readonly");
+        }).then(() => {
+            done();
+        });
+    });
+});
+
+test('test open options', function(t, done) {
+    var index = noise.open("tmp/openoptions", {createIfMissing: true, errorIfExists: true});
+    index.add({_id:"a",foo:"bar"}).then(() => {
+        return index.close();
+    }).then(() => {
+        var again = noise.open("tmp/openoptions", {errorIfExists: true});
+        return again.query('find {}');
+    }).then(() => {
+        assert.ok(false, "open should have failed");
+    }).catch(error => {
+        assert.ok(/already exists/.test(error), "expected: " + error);
+        return noise.drop("tmp/openoptions").then(() => {
+            done();
+        });
+    });
+});
+
+test('test unknown open options', function() {
+    assert.throws(() => noise.open("tmp/unknownoptions", {createIfMising: true}),
+                  /Unknown option 'createIfMising'/);
+    assert.throws(() => noise.open("tmp/unknownoptions", {blockCacheSize: 1 << 20}),
+                  /Option 'blockCacheSize' isn't supported/);
+    assert.ok(!require('fs').existsSync("tmp/unknownoptions"), "nothing created");
+});
+
+test('test memory usage', function(t, done) {
+    var index = noise.open("tmp/memoryusage", true);
+    index.add({_id:"a",foo:"bar"}).then(() => {
+        return noise.memoryUsage();
+    }).then(usage => {
+        var entry = usage.indexes.find(entry => entry.name == "tmp/memoryusage");
+        assert.equal(entry.openCount, 1, "index is open");
+        assert.equal(entry.readOnlyView, false, "not a view");
+        assert.ok(usage.rss > 0, "resident memory");
+        return index.close();
+    }).then(() => {
+        return noise.memoryUsage();
+    }).then(usage => {
+        assert.ok(!usage.indexes.some(entry => entry.name == "tmp/memoryusage"),
+                  "closed index isn't reported");
+        return noise.drop("tmp/memoryusage");
+    }).then(() => {
+        done();
+    }).catch(error => {
+        console.log(error);
+        assert.ok(false, "should be no error");
+    });
+});
+
+test('test compact', function(t, done) {
+    var index = noise.open("tmp/compact", {createIfMissing: true,
+                                           compactDeletedThreshold: 1000});
+    var docs = [];
+    for (var i = 0; i < 100; i++) {
+        docs.push({_id: "doc" + i, text: "some words to index " + i});
+    }
+    index.add(docs).then(() => {
+        return index.delete(docs.map(doc => doc._id));
+    }).then(() => {
+        return index.compact();
+    }).then(sizes => {
+        assert.equal(typeof sizes.bytesBefore, "number", "size before");
+        assert.equal(typeof sizes.bytesAfter, "number", "size after");
+        return index.add(docs);
+    }).then(() => {
+        return index.delete(docs.map(doc => doc._id));
+    }).then(() => {
+        return index.compact({start: "doc0", end: "doc99"}).then(() => {
+            assert.ok(false, "compacting a range should have failed");
+        }, error => {
+            assert.ok(/takes no options/.test(error), "expected: " + error);
+        });
+    }).then(() => {
+        return index.compact();
+    }).then(sizes => {
+        assert.ok(sizes.bytesAfter < sizes.bytesBefore, "compaction reclaims space");
+        return index.query('find {text: ~= "words"}');
+    }).then(iter => {
+        assert.deepEqual([...iter], [], "documents are still deleted");
+        return index.close();
+    }).then(() => {
+        return noise.drop("tmp/compact");
+    }).then(() => {
+        done();
+    }).catch(error => {
+        console.log(error);
+        assert.ok(false, "should be no error");
+    });
+});
+
+test('test list', function(t, done) {
+    var fs = require('fs');
+    // the same index by another name
+    var index1 = noise.open("./tmp/list/one", true);
+    var files;
+    var index2 = noise.open("tmp/list/nested/two", true);
+    index1.add([{_id:"a"}, {_id:"b"}]).then(() => {
+        return index2.add({_id:"c"});
+    }).then(() => {
+        return index2.close();
+    }).then(() => {
+        files = fs.readdirSync("tmp/list/nested/two");
+        return noise.list("tmp/list");
+    }).then(indexes => {
+        assert.deepEqual(indexes.map(index => [index.name, index.docCount, index.open]),
+                         [["tmp/list/nested/two", 1, false], ["tmp/list/one", 2, true]],
+                         "indexes are found");
+        assert.ok(indexes.every(index => index.size > 0), "sizes are reported");
+        assert.deepEqual(fs.readdirSync("tmp/list/nested/two"), files,
+                         "closed index isn't written to");
+        return index1.close();
+    }).then(() => {
+        return noise.drop("tmp/list/one");
+    }).then(() => {
+        return noise.drop("tmp/list/nested/two");
+    }).then(() => {
+        done();
+    }).catch(error => {
+        console.log(error);
+        assert.ok(false, "should be no error");
+    });
+});
+
+test('test drop safety', function(t, done) {
+    var fs = require('fs');
+    fs.mkdirSync("tmp/notanindex", {recursive: true});
+    fs.writeFileSync("tmp/notanindex/important.txt", "keep me");
+    var index = noise.open("tmp/dropsafety", true);
+    index.add({_id:"a",foo:"bar"}).then(() => {
+        return index.close();
+    }).then(() => {
+        return noise.drop("tmp/notanindex").then(() => {
+            assert.ok(false, "drop should have failed");
+        }).catch(error => {
+            assert.ok(/isn't a noise index/.test(error), "expected: " + error);
+            assert.ok(fs.existsSync("tmp/notanindex/important.txt"), "file kept");
+        });
+    }).then(() => {
+        return noise.drop("tmp/dropsafety", {dryRun: true});
+    }).then(resp => {
+        assert.equal(resp.name, "tmp/dropsafety", "name");
+        assert.ok(resp.files.indexOf("CURRENT") != -1, "files listed");
+        assert.ok(resp.size > 0, "size");
+        assert.ok(fs.existsSync("tmp/dropsafety/CURRENT"), "nothing deleted");
+        noise.setBaseDir("tmp/basedir");
+        fs.mkdirSync("tmp/basedir", {recursive: true});
+        return noise.drop("tmp/dropsafety").then(() => {
+            assert.ok(false, "drop should have failed");
+        }).catch(error => {
+            assert.ok(/outside of the base directory/.test(error), "expected: " + error);
+            noise.setBaseDir(null);
+        });
+    }).then(() => {
+        // files that merely look like RocksDB's aren't enough
+        fs.mkdirSync("tmp/lockonly", {recursive: true});
+        fs.writeFileSync("tmp/lockonly/LOCK", "");
+        fs.writeFileSync("tmp/lockonly/LOG", "");
+        return noise.drop("tmp/lockonly").then(() => {
+            assert.ok(false, "drop should have failed");
+        }, error => {
+            assert.ok(/isn't a noise index/.test(error), "expected: " + error);
+            assert.ok(fs.existsSync("tmp/lockonly/LOG"), "file kept");
+        });
+    }).then(() => {
+        fs.mkdirSync("tmp/emptydir", {recursive: true});
+        return noise.drop("tmp/emptydir");
+    }).then(() => {
+        assert.ok(!fs.existsSync("tmp/emptydir"), "empty directory dropped");
+        return noise.drop("tmp/dropsafety");
+    }).then(() => {
+        assert.ok(!fs.existsSync("tmp/dropsafety"), "index dropped");
+        done();
+    }).catch(error => {
+        noise.setBaseDir(null);
+        console.log(error);
+        assert.ok(false, "should be no error");
+    });
+});
+
+test('test force drop', function(t, done) {
+    var index1 = noise.open("tmp/forcedrop", true);
+    var index2;
+    index1.add({_id:"a",foo:"bar"}).then(() => {
+        // only once the first instance created the index
+        index2 = noise.open("tmp/forcedrop", false);
+        return index2.query('find {foo: == "bar"}');
+    }).then(iter => {
+        assert.deepEqual([...iter], ["a"], "found a on instance 2");
+        return noise.drop("tmp/forcedrop").then(() => {
+            assert.ok(false, "drop without force should have failed");
+        }).catch(error => {
+            assert.ok(/still open/.test(error), "expected: " + error);
+        });
+    }).then(() => {
+        return noise.drop("tmp/forcedrop", {force: true});
+    }).then(resp => {
+        assert.equal(resp, true, "index dropped");
+        return index1.add({_id:"b",foo:"bar"}).then(() => {
+            assert.ok(false, "add should have failed");
+        }).catch(error => {
+            assert.ok(/index dropped/.test(error), "expected: " + error);
+        });
+    }).then(() => {
+        return index2.query('find {}').then(() => {
+            assert.ok(false, "query should have failed");
+        }).catch(error => {
+            assert.ok(/index dropped/.test(error), "expected: " + error);
+        });
+    }).then(() => {
+        return index1.close();
+    }).then(() => {
+        return index2.close();
+    }).then(() => {
+        assert.ok(!require('fs').existsSync("tmp/forcedrop"), "files deleted");
+        done();
+    }).catch(error => {
+        console.log(error);
+        assert.ok(false, "should be no error");
+    });
+});
+
+test('test copy and rename', function(t, done) {
+    var index = noise.open("tmp/copysrc", true);
+    index.add([{_id:"a",foo:"bar"}, {_id:"b", foo:"baz"}]).then(() => {
+        return noise.copy("tmp/copysrc", "tmp/copydest").then(() => {
+            assert.ok(false, "copy of open index should have failed");
+        }).catch(error => {
+            assert.ok(/still open/.test(error), "expected: " + error);
+            return index.close();
+        });
+    }).then(() => {
+        noise.setBaseDir("tmp");
+        return noise.copy("tmp/copysrc", "tmp/../copyescaped").then(() => {
+            assert.ok(false, "copy out of the base directory should have failed");
+        }).catch(error => {
+            assert.ok(/outside of the base directory/.test(error), "expected: " + error);
+            return noise.rename("tmp/copysrc", "tmp/../copyescaped");
+        }).then(() => {
+            assert.ok(false, "rename out of the base directory should have failed");
+        }).catch(error => {
+            assert.ok(/outside of the base directory/.test(error), "expected: " + error);
+            assert.ok(!require('fs').existsSync("copyescaped"), "nothing created");
+            noise.setBaseDir(null);
+        });
+    }).then(() => {
+        return noise.copy("tmp/copysrc", "tmp/copydest");
+    }).then(() => {
+        return noise.rename("tmp/copysrc", "tmp/copydest").then(() => {
+            assert.ok(false, "rename onto existing index should have failed");
+        }).catch(error => {
+            assert.ok(/already exists/.test(error), "expected: " + error);
+        });
+    }).then(() => {
+        return noise.rename("tmp/copydest", "tmp/renamed");
+    }).then(() => {
+        var renamed = noise.open("tmp/renamed");
+        return renamed.add({_id:"c",foo:"bar"}).then(() => {
+            return renamed.query('find {foo: == "bar"}');
+        }).then(iter => {
+            assert.deepEqual([...iter], ["a", "c"], "renamed copy has docs");
+            return renamed.close();
+        });
+    }).then(() => {
+        var src = noise.open("tmp/copysrc");
+        return src.query('find {foo: == "bar"}').then(iter => {
+            assert.deepEqual([...iter], ["a"], "source unchanged");
+            return src.close();
+        });
+    }).then(() => {
+        return noise.drop("tmp/copysrc");
+    }).then(() => {
+        return noise.drop("tmp/renamed");
+    }).then(() => {
+        done();
+    }).catch(error => {
+        console.log(error);
+        assert.ok(false, "should be no error");
+    });
+});
+
+test('test in memory', function(t, done) {
+    var index1 = noise.open(":memory:");
+    var index2 = noise.open(":memory:");
+    var named1 = noise.open("tmp/inmemory", {inMemory: true});
+    var named2;
+    index1.add({_id:"a",foo:"bar"}).then(() => {
+        return index2.query('find {}');
+    }).then(iter => {
+        assert.deepEqual([...iter], [], "each :memory: index is a new one");
+        return named1.add({_id:"b",foo:"bar"});
+    }).then(() => {
+        named2 = noise.open("tmp/inmemory", {inMemory: true});
+        return named2.query('find {foo: == "bar"}');
+    }).then(iter => {
+        assert.deepEqual([...iter], ["b"], "named in-memory index is shared");
+        assert.ok(!require('fs').existsSync("tmp/inmemory"), "nothing on disk");
+        return Promise.all([index1.close(), index2.close(), named1.close(), named2.close()]);
+    }).then(() => {
+        var reopened = noise.open("tmp/inmemory", {inMemory: true});
+        return reopened.query('find {}').then(iter => {
+            assert.deepEqual([...iter], [], "gone after last close");
+            return reopened.close();
+        });
+    }).then(() => {
+        return noise.memoryUsage();
+    }).then(usage => {
+        assert.ok(!usage.indexes.some(entry => /^:memory:/.test(entry.name)), "all closed");
+        done();
+    }).catch(error => {
+        console.log(error);
+        assert.ok(false, "should be no error");
+    });
+});
+
+test('test temp index', function(t, done) {
+    var fs = require('fs');
+    var index = noise.openTemp("tmp/scratch");
+    var name = index.name;
+    var other;
+    assert.ok(name.startsWith("tmp/scratch-tmp-"), "unique name");
+    index.add({_id:"a",foo:"bar"}).then(() => {
+        assert.ok(fs.existsSync(name + "/CURRENT"), "index on disk");
+        other = noise.open(name);
+        return other.query('find {}');
+    }).then(iter => {
+        assert.deepEqual([...iter], ["a"], "second instance open");
+        return index.close();
+    }).then(() => {
+        assert.ok(fs.existsSync(name), "kept while an instance is open");
+        return other.query('find {foo: == "bar"}');
+    }).then(iter => {
+        assert.deepEqual([...iter], ["a"], "doc a found");
+        return other.close();
+    }).then(() => {
+        assert.ok(!fs.existsSync(name), "dropped after the last close");
+        done();
+    }).catch(error => {
+        console.log(error);
+        assert.ok(false, "should be no error");
+    });
+});
+
+test('test shutdown', function(t, done) {
+    var fs = require('fs');
+    var path = require('path');
+    var childProcess = require('child_process');
+    // shutting down ends noise for the whole process, so do it in another
+    // one. It needs its own directory, as the socket is in the current one.
+    fs.mkdirSync("tmp/shutdown", {recursive: true});
+    var script = `
+        var fs = require('fs');
+        var noise = require(${JSON.stringify(path.resolve("lib/noise.js"))});
+        var index = noise.open("index", true);
+        var added = index.add({_id: "a", foo: "bar"});
+        noise.shutdown().then(closed => {
+            return added.then(ids => {
+                return noise.open("index").query('find {}').catch(error => {
+                    console.log(JSON.stringify({closed: closed, ids: ids,
+                                                socket: fs.existsSync("echo.sock"),
+                                                error: error.message}));
+                });
+            });
+        });
+    `;
+    childProcess.execFile(process.execPath, ["-e", script], {cwd: "tmp/shutdown"},
+                          (error, stdout) => {
+        assert.ok(!error, "child exited cleanly");
+        assert.deepEqual(JSON.parse(stdout),
+                         {closed: true, ids: ["a"], socket: false,
+                          error: "noise is shut down"},
+                         "queued add done, everything closed");
+        var index = noise.open("tmp/shutdown/index");
+        index.query('find {foo: == "bar"}').then(iter => {
+            assert.deepEqual([...iter], ["a"], "added doc was written");
+            return index.close();
+        }).then(() => {
+            return noise.drop("tmp/shutdown/index");
+        }).then(() => {
+            done();
+        }).catch(error => {
+            console.log(error);
+            assert.ok(false, "should be no error");
+        });
+    });
+});
+
+test('test signal handled by the application', function(t, done) {
+    var fs = require('fs');
+    var path = require('path');
+    var childProcess = require('child_process');
+    fs.mkdirSync("tmp/signal", {recursive: true});
+    // the listener is added after noise was loaded, noise mustn't kill the
+    // process anyway
+    var script = `
+        var noise = require(${JSON.stringify(path.resolve("lib/noise.js"))});
+        var index = noise.open("index", true);
+        process.on('SIGTERM', () => {
+            setTimeout(() => {
+                index.add({_id: "a"}).then(ids => {
+                    console.log(JSON.stringify(ids));
+                    return noise.shutdown();
+                });
+            }, 100);
+        });
+        process.kill(process.pid, 'SIGTERM');
+    `;
+    childProcess.execFile(process.execPath, ["-e", script], {cwd: "tmp/signal"},
+                          (error, stdout) => {
+        assert.ok(!error, "child exited cleanly: " + error);
+        assert.deepEqual(JSON.parse(stdout), ["a"], "still running after the signal");
+        noise.drop("tmp/signal/index").then(() => {
+            done();
+        });
+    });
+});
+
+test('test worker threads', function(t, done) {
+    var path = require('path');
+    var { Worker } = require('worker_threads');
+    var index = noise.open("tmp/workers", true);
+    // each worker opens the same index and adds documents to it
+    var script = `
+        var { parentPort, workerData } = require('worker_threads');
+        var noise = require(${JSON.stringify(path.resolve("lib/noise.js"))});
+        var index = noise.open("tmp/workers");
+        var docs = [];
+        for (var i = 0; i < 20; i++) {
+            docs.push({_id: workerData + i, worker: workerData});
+        }
+        index.add(docs).then(() => {
+            return index.close();
+        }).then(() => {
+            parentPort.postMessage("done");
+        }).catch(error => {
+            parentPort.postMessage(error.message);
+        });
+    `;
+    var runWorker = (name) => {
+        return new Promise((resolve, reject) => {
+            var worker = new Worker(script, {eval: true, workerData: name});
+            worker.on('message', resolve);
+            worker.on('error', reject);
+        });
+    };
+    index.query('find {}').then(iter => {
+        assert.deepEqual([...iter], [], "index is open");
+        return Promise.all([runWorker("one"), runWorker("two"), runWorker("three")]);
+    }).then(results => {
+        assert.deepEqual(results, ["done", "done", "done"], "workers added docs");
+        return index.query('find {} return count()');
+    }).then(iter => {
+        assert.deepEqual([...iter], [60], "all docs in the shared index");
+        return index.info();
+    }).then(info => {
+        assert.equal(info.openCount, 1, "workers closed their instances");
+        return index.close();
+    }).then(() => {
+        return noise.drop("tmp/workers");
+    }).then(() => {
+        done();
+    }).catch(error => {
+        console.log(error);
+        assert.ok(false, "should be no error");
+    });
+});
+
+test('test daemon', function(t, done) {
+    var fs = require('fs');
+    var path = require('path');
+    var childProcess = require('child_process');
+    // the daemon runs in another process, in its own directory as the socket
+    // noise uses internally is in the current one
+    fs.mkdirSync("tmp/daemon", {recursive: true});
+    var script = `
+        var noise = require(${JSON.stringify(path.resolve("lib/noise.js"))});
+        noise.startDaemon("noise.sock").then(() => {
+            console.log("ready");
+        });
+    `;
+    var daemon = childProcess.spawn(process.execPath, ["-e", script], {cwd: "tmp/daemon"});
+    var name = path.resolve("tmp/daemon/index");
+    var options = {daemon: "tmp/daemon/noise.sock", createIfMissing: true};
+    var index, index2;
+    daemon.stdout.once('data', () => {
+        index = noise.open(name, options);
+        index2 = noise.open(name, options);
+        index.add([{_id:"a",foo:"bar"}, {_id:"b",foo:"baz"}]).then(ids => {
+            assert.deepEqual(ids, ["a", "b"], "added through the daemon");
+            return index2.query('find {foo: == @foo}', {foo: "bar"});
+        }).then(iter => {
+            assert.deepEqual([...iter], ["a"], "found by the other instance");
+            return index2.info();
+        }).then(info => {
+            assert.equal(info.openCount, 2, "both instances open in the daemon");
+            return noise.open(name).query('find {}');
+        }).then(() => {
+            assert.ok(false, "index is locked by the daemon");
+        }, error => {
+            assert.ok(/lock/i.test(error), "expected: " + error);
+            // a read-only instance doesn't need the lock
+            var readOnly = noise.open(name, {readOnly: true});
+            return readOnly.query('find {foo: == "baz"}').then(iter => {
+                assert.deepEqual([...iter], ["b"], "read only instance next to the daemon");
+                return readOnly.add({_id:"c",foo:"baz"});
+            }).then(() => {
+                assert.ok(false, "add should have failed");
+            }, error => {
+                assert.ok(/read-only/.test(error), "expected: " + error);
+                return readOnly.close();
+            });
+        }).then(() => {
+            // the daemon knows the index is open, this process doesn't
+            return noise.drop(name, {daemon: "tmp/daemon/noise.sock"});
+        }).then(() => {
+            assert.ok(false, "drop should have failed");
+        }, error => {
+            assert.ok(/still open/.test(error), "expected: " + error);
+            return Promise.all([index.close(), index2.close()]);
+        }).then(() => {
+            return noise.copy(name, name + "copy", {daemon: "tmp/daemon/noise.sock"});
+        }).then(() => {
+            return noise.list(path.resolve("tmp/daemon"), {daemon: "tmp/daemon/noise.sock"});
+        }).then(indexes => {
+            assert.deepEqual(indexes.map(index => path.basename(index.name)),
+                             ["index", "indexcopy"], "listed by the daemon");
+            return noise.drop(name + "copy", {daemon: "tmp/daemon/noise.sock"});
+        }).then(() => {
+            assert.ok(!fs.existsSync(name + "copy"), "copy dropped by the daemon");
+            daemon.on('exit', () => {
+                assert.ok(!fs.existsSync("tmp/daemon/noise.sock"), "socket removed");
+                var missing = noise.open(name, {daemon: "tmp/daemon/noise.sock"});
+                missing.query('find {}').then(() => {
+                    assert.ok(false, "daemon is gone");
+                }, error => {
+                    assert.ok(/daemon/.test(error), "expected: " + error);
+                    return noise.drop(name);
+                }).then(() => {
+                    done();
+                });
+            });
+            daemon.kill('SIGTERM');
+        }).catch(error => {
+            console.log(error);
+            assert.ok(false, "should be no error");
+        });
+    });
+});
+
+test('test wire protocol', function(t, done) {
+    var net = require('net');
+    // a frame of an unknown version of the protocol gets an error back
+    var socket = net.connect("echo.sock", () => {
+        var frame = Buffer.alloc(10);
+        frame.writeUInt8(99, 0);
+        socket.write(frame);
+    });
+    var data = [];
+    socket.on('data', chunk => data.push(chunk));
+    socket.on('end', () => {
+        var frame = Buffer.concat(data);
+        assert.equal(frame.readUInt8(0), 1, "protocol version");
+        assert.equal(frame.readUInt8(1), 65, "error response");
+        assert.ok(/unsupported protocol version 99/.test(frame.toString('utf8', 14)),
+                  "expected: " + frame.toString('utf8', 14));
+        // responses larger than a socket buffer arrive in pieces
+        var index = noise.open("tmp/wire", true);
+        var docs = [];
+        for (var i = 0; i < 5000; i++) {
+            docs.push({_id: "doc" + i, text: "some text to make the frames larger " + i});
+        }
+        index.add(docs).then(ids => {
+            assert.equal(ids.length, 5000, "all added");
+            return index.query('find {text: ~= "larger"} return ._id');
+        }).then(iter => {
+            assert.equal([...iter].length, 5000, "all found");
+            return index.close();
+        }).then(() => {
+            return noise.drop("tmp/wire");
+        }).then(() => {
+            done();
+        }).catch(error => {
+            console.log(error);
+            assert.ok(false, "should be no error");
+        });
+    });
+});
+
+test('test http api', function(t, done) {
+    var http = require('http');
+    var index = noise.open("tmp/http", true);
+    var server;
+    var request = (method, path, body, headers) => {
+        if (headers == undefined) {
+            headers = body == undefined ? {} : {'Content-Type': 'application/json'};
+        }
+        return new Promise((resolve, reject) => {
+            var req = http.request({port: server.address().port, method: method, path: path,
+                                    headers: headers}, res => {
+                var data = "";
+                res.on('data', chunk => data += chunk);
+                res.on('end', () => resolve({status: res.statusCode,
+                                             type: res.headers['content-type'],
+                                             body: data}));
+            });
+            req.on('error', reject);
+            req.end(body == undefined ? undefined : JSON.stringify(body));
+        });
+    };
+    noise.serve({indexes: {docs: index}, maxBodySize: 1000}).then(s => {
+        server = s;
+        return request("POST", "/docs/docs", [{_id: "a", foo: "bar"}, {_id: "b", foo: "baz"}]);
+    }).then(res => {
+        assert.equal(res.status, 200, "added");
+        assert.deepEqual(JSON.parse(res.body), ["a", "b"], "ids");
+        return request("POST", "/docs/query",
+                       {query: 'find {foo: == @foo} return {id: ._id}', params: {foo: "bar"}});
+    }).then(res => {
+        assert.equal(res.type, "application/x-ndjson", "streamed as NDJSON");
+        assert.equal(res.body, '{"id":"a"}\n', "query with params");
+        return request("GET", "/docs/query?q=" + encodeURIComponent('find {}'));
+    }).then(res => {
+        assert.equal(res.body.split("\n").filter(line => line).length, 2, "query in the URL");
+        return request("DELETE", "/docs/docs/a");
+    }).then(res => {
+        assert.deepEqual(JSON.parse(res.body), {deleted: true}, "deleted");
+        return request("DELETE", "/docs/docs/a");
+    }).then(res => {
+        assert.equal(res.status, 404, "already deleted");
+        return request("POST", "/docs/query", {query: 'find {foo: =='});
+    }).then(res => {
+        assert.equal(res.status, 400, "bad query");
+        assert.ok(JSON.parse(res.body).error, "error message");
+        return request("GET", "/other/query?q=find%20{}");
+    }).then(res => {
+        assert.equal(res.status, 404, "unknown index");
+        return request("GET", "/");
+    }).then(res => {
+        assert.deepEqual(JSON.parse(res.body), ["docs"], "served indexes");
+        return request("POST", "/docs/docs", {_id: "c"}, {'Content-Type': 'text/plain'});
+    }).then(res => {
+        assert.equal(res.status, 415, "not JSON");
+        return request("POST", "/docs/docs", {_id: "c", foo: "x".repeat(1000)});
+    }).then(res => {
+        assert.equal(res.status, 413, "body too large");
+        return request("POST", "/docs/docs", {_id: "c"},
+                       {'Content-Type': 'application/json', 'Origin': 'http://example.com'});
+    }).then(res => {
+        assert.equal(res.status, 403, "other origin");
+        var origin = "http://localhost:" + server.address().port;
+        return request("POST", "/docs/docs", {_id: "c"},
+                       {'Content-Type': 'application/json', 'Origin': origin});
+    }).then(res => {
+        assert.equal(res.status, 200, "same origin");
+        return request("GET", "/", undefined, {'Host': 'rebound.example.com'});
+    }).then(res => {
+        assert.equal(res.status, 403, "other host");
+        return request("GET", "/", undefined, {'Host': '127.0.0.1:' + server.address().port});
+    }).then(res => {
+        assert.equal(res.status, 200, "local host");
+        var docs = [];
+        for (var i = 0; i < 10000; i++) {
+            docs.push({_id: "big" + i, text: "x".repeat(2000)});
+        }
+        return index.add(docs);
+    }).then(() => {
+        // a client that doesn't read the results doesn't block the instance
+        return new Promise((resolve, reject) => {
+            var path = "/docs/query?q=" + encodeURIComponent('find {} return .');
+            http.get({port: server.address().port, path: path}, res => {
+                res.pause();
+                var timer = setTimeout(() => reject(new Error("instance blocked")), 5000);
+                index.info().then(info => {
+                    clearTimeout(timer);
+                    res.destroy();
+                    resolve();
+                }, reject);
+            }).on('error', () => {});
+        });
+    }).then(() => {
+        server.close();
+        return index.close();
+    }).then(() => {
+        return noise.drop("tmp/http");
+    }).then(() => {
+        done();
+    }).catch(error => {
+        console.log(error);
+        assert.ok(false, "should be no error");
+    });
+});
+
+// The path of a binary of the native build, the one the addon came from.
+var nativeBin = function(name) {
+    var fs = require('fs');
+    var bin = ["release", "debug"].map(profile => "native/target/" + profile + "/" + name)
+        .filter(bin => fs.existsSync(bin))
+        .sort((a, b) => fs.statSync(b).mtimeMs - fs.statSync(a).mtimeMs)[0];
+    assert.ok(bin, name + " was built");
+    return bin;
+};
+
+test('test json-rpc', function(t, done) {
+    var fs = require('fs');
+    var childProcess = require('child_process');
+    var bin = nativeBin("noise-rpc");
+    var requests = [
+        {jsonrpc: "2.0", id: 1, method: "open",
+         params: {name: "tmp/rpc", options: {createIfMissing: true}}},
+        {jsonrpc: "2.0", id: 2, method: "add",
+         params: {handle: 1, docs: [{_id: "a", foo: "bar"}, {_id: "b", foo: "baz"},
+                                   {_id: "c", foo: "baz"}]}},
+        {jsonrpc: "2.0", id: 3, method: "query",
+         params: {handle: 1, query: "find {foo: == @foo}", params: {foo: "baz"}}},
+        [{jsonrpc: "2.0", id: 4, method: "delete", params: {handle: 1, ids: ["a", "d"]}},
+         {jsonrpc: "2.0", method: "close", params: {handle: 1}}],
+        {jsonrpc: "2.0", id: 5, method: "query", params: {handle: 1, query: "find {}"}},
+        {jsonrpc: "2.0", id: 6, method: "drop", params: {name: "tmp/rpc"}},
+        {jsonrpc: "2.0", id: 7, method: "compact"},
+    ];
+    var input = requests.map(request => JSON.stringify(request)).join("\n") + "\nnot json\n";
+    childProcess.execFile(bin, [], {}, (error, stdout) => {
+        assert.ok(!error, "exited cleanly");
+        var responses = stdout.trim().split("\n").map(line => JSON.parse(line));
+        assert.deepEqual(responses.slice(0, 4), [
+            {jsonrpc: "2.0", id: 1, result: 1},
+            {jsonrpc: "2.0", id: 2, result: ["a", "b", "c"]},
+            {jsonrpc: "2.0", id: 3, result: ["b", "c"]},
+            [{jsonrpc: "2.0", id: 4, result: [true, false]}],
+        ], "index used through JSON-RPC");
+        assert.equal(responses[4].error.code, -32602, "closed handle");
+        assert.deepEqual(responses[5], {jsonrpc: "2.0", id: 6, result: true}, "dropped");
+        assert.equal(responses[6].error.code, -32601, "unknown method");
+        assert.equal(responses[7].error.code, -32700, "parse error");
+        assert.ok(!fs.existsSync("tmp/rpc"), "index is gone");
+        done();
+    }).stdin.end(input);
+});
+
+test('test cli', function(t, done) {
+    var fs = require('fs');
+    var childProcess = require('child_process');
+    var bin = nativeBin("noise");
+    var run = function(args, input) {
+        return childProcess.spawnSync(bin, args, {input: input || "", encoding: "utf8"});
+    };
+    var lines = function(stdout) {
+        return stdout.trim().split("\n").map(line => JSON.parse(line));
+    };
+    var imported = run(["import", "tmp/cli"],
+                       '{"_id": "a", "foo": "bar"}\n{"_id": "b", "foo": "baz"}\n' +
+                       'not json\n{"_id": "c", "foo": "baz", "n": 2}\n');
+    assert.equal(imported.status, 0, "imported");
+    var summary = JSON.parse(imported.stdout);
+    assert.equal(summary.imported, 3, "imported docs");
+    assert.deepEqual(summary.errors.map(error => error.line), [3], "bad line reported");
+    assert.deepEqual(JSON.parse(run(["add", "tmp/cli"], '[{"_id": "d", "foo": "qux"}]').stdout),
+                     ["d"], "added");
+    assert.deepEqual(lines(run(["query", "tmp/cli", "find {foo: == @foo} return ._id",
+                                "--param", "foo=baz"]).stdout), ["b", "c"], "query");
+    assert.deepEqual(lines(run(["query", "tmp/cli", "find {n: == @n} return ._id",
+                                "--param", "n=2"]).stdout), ["c"], "JSON param");
+    assert.deepEqual(JSON.parse(run(["get", "tmp/cli", "a"]).stdout), {_id: "a", foo: "bar"},
+                     "get");
+    var missing = run(["get", "tmp/cli", "x"]);
+    assert.equal(missing.status, 1, "missing doc fails");
+    assert.ok(/not found/.test(missing.stderr), "missing doc reported");
+    assert.deepEqual(JSON.parse(run(["delete", "tmp/cli", "a", "x"]).stdout), [true, false],
+                     "deleted");
+    assert.equal(JSON.parse(run(["stats", "tmp/cli"]).stdout).docCount, 3, "stats");
+    var exported = run(["export", "tmp/cli"]).stdout;
+    assert.deepEqual(lines(exported).map(doc => doc._id), ["b", "c", "d"], "exported");
+    assert.equal(JSON.parse(run(["import", "tmp/cli2"], exported).stdout).imported, 3,
+                 "exported docs imported");
+    assert.ok("bytesAfter" in JSON.parse(run(["compact", "tmp/cli"]).stdout), "compacted");
+    assert.equal(run(["query", "tmp/cli", "find {"]).status, 1, "bad query fails");
+    assert.equal(run(["drop", "tmp/cli", "--bogus"]).status, 1, "unknown option fails");
+    // reading doesn't need the lock of the index
+    var index = noise.open("tmp/cli");
+    index.info().then(() => {
+        assert.equal(run(["export", "tmp/cli"]).stdout, exported, "exported while open");
+        assert.equal(JSON.parse(run(["stats", "tmp/cli"]).stdout).docCount, 3, "stats while open");
+        assert.deepEqual(lines(run(["query", "tmp/cli", "find {foo: == \"qux\"} return ._id"])
+                               .stdout), ["d"], "query while open");
+        assert.ok(/"d"/.test(run(["repl", "tmp/cli"], 'find {foo: == "qux"}\n').stdout),
+                  "repl while open");
+        assert.equal(run(["add", "tmp/cli"], '{"_id": "e"}').status, 1, "add needs the lock");
+        return index.close();
+    }).then(() => {
+        assert.equal(JSON.parse(run(["drop", "tmp/cli"]).stdout), true, "dropped");
+        assert.equal(JSON.parse(run(["drop", "tmp/cli2"]).stdout), true, "dropped copy");
+        assert.ok(!fs.existsSync("tmp/cli"), "index is gone");
+        done();
+    });
+});
+
+test('test repl', function(t, done) {
+    var childProcess = require('child_process');
+    var bin = nativeBin("noise");
+    childProcess.spawnSync(bin, ["import", "tmp/repl"],
+                           {input: '{"_id": "a", "foo": "bar"}\n{"_id": "b", "foo": "baz"}\n'});
+    // without a terminal the lines are read as they are
+    var input = ['@foo = "baz"',
+                 'find {foo: == @foo}',
+                 'find {',
+                 '  foo: == "bar"}',
+                 'return {id: ._id};',
+                 '.unset foo',
+                 'find {foo: == @foo}',
+                 '.exit',
+                 'find {}'].join("\n") + "\n";
+    var repl = childProcess.spawnSync(bin, ["repl", "tmp/repl"], {input: input, encoding: "utf8"});
+    assert.equal(repl.status, 0, "exited cleanly");
+    var output = repl.stdout.replace(/\(\d+\.\d ms\)/g, "(ms)");
+    assert.equal(output, '"b"\n1 result (ms)\n{\n  "id": "a"\n}\n1 result (ms)\n',
+                 "results pretty-printed and timed");
+    assert.ok(/No matching parameter for @foo/.test(repl.stderr), "unset parameter");
+    childProcess.spawnSync(bin, ["drop", "tmp/repl"]);
+    done();
+});